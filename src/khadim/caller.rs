@@ -2,11 +2,17 @@ use std::pin::Pin;
 use std::future::Future;
 use std::boxed::Box;
 
-use super::response::{ResponseWriter, Request};
+use super::response::{ResponseWriter, Request, Response};
 use super::http_status::HttpStatus;
+use super::error::HandlerError;
 use crate::api_callback;
 
-pub type AsyncReturn = Result<Pin<Box<dyn Future<Output = String> + Send>>, Box<dyn std::error::Error>>;
+/// What a handler returns. Errors before or while producing the response go
+/// to the error handler.
+pub type AsyncReturn = Result<Pin<Box<dyn Future<Output = Result<Response, HandlerError>> + Send>>, HandlerError>;
+
+// What a WebSocket callback returns, the connection closes when it resolves
+pub type WsReturn = Pin<Box<dyn Future<Output = ()> + Send>>;
 
 #[api_callback]
 pub fn default_404(_request: Request, mut writer: ResponseWriter){
@@ -15,7 +21,29 @@ pub fn default_404(_request: Request, mut writer: ResponseWriter){
 }
 
 #[api_callback]
-pub fn default_500(_request: Request, mut writer: ResponseWriter){
-    writer.set_status(HttpStatus::InternalServerError);
+pub fn default_405(_request: Request, mut writer: ResponseWriter){
+    writer.set_status(HttpStatus::MethodNotAllowed);
+    writer.response()
+}
+
+#[api_callback]
+pub fn default_options(_request: Request, mut writer: ResponseWriter){
+    writer.set_status(HttpStatus::NoContent);
+    writer.response()
+}
+
+/// Answers with the status of the error, and with its message as the body
+/// unless it is a server error.
+pub fn default_error(_request: Request, mut writer: ResponseWriter, error: HandlerError) -> AsyncReturn{
+    writer.set_status(error.status);
+    if !error.status.is_server_error(){
+        writer.set_body(error.message);
+    }
+    writer.response()
+}
+
+#[api_callback]
+pub fn default_426(_request: Request, mut writer: ResponseWriter){
+    writer.set_status(HttpStatus::UpgradeRequired);
     writer.response()
 }
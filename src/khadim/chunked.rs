@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::fmt;
+
+// Longest chunk-size line (extensions included) or trailer line we accept
+const MAX_LINE_LENGTH: usize = 4096;
+const MAX_TRAILER_SIZE: usize = 8192;
+
+// Framing headers a sender is not allowed to move into the trailers
+const FORBIDDEN_TRAILERS: [&str; 6] = [
+    "content-length", "transfer-encoding", "content-type", "host", "trailer", "content-encoding"
+];
+
+#[derive(Debug, PartialEq)]
+pub enum ChunkedError{
+    Malformed(&'static str),
+    TooLarge
+}
+
+impl fmt::Display for ChunkedError{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self{
+            ChunkedError::Malformed(reason) => write!(f, "Malformed chunked body: {}", reason),
+            ChunkedError::TooLarge => write!(f, "Chunked body exceeds the size limit")
+        }
+    }
+}
+
+impl std::error::Error for ChunkedError{}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State{
+    Size,
+    Data(usize),
+    DataEnd,
+    Trailers,
+    Done
+}
+
+/// Incremental decoder for `Transfer-Encoding: chunked` bodies. The caller
+/// keeps appending to one buffer and hands the whole of it to `decode` after
+/// every read; already decoded bytes are skipped.
+#[derive(Debug)]
+pub struct ChunkedDecoder{
+    state: State,
+    position: usize,
+    body: Vec<u8>,
+    trailers: HashMap<String, String>,
+    trailer_size: usize,
+    max_body_size: usize
+}
+
+impl ChunkedDecoder{
+    pub fn new(max_body_size: usize) -> Self{
+        ChunkedDecoder{
+            state: State::Size,
+            position: 0,
+            body: Vec::new(),
+            trailers: HashMap::new(),
+            trailer_size: 0,
+            max_body_size
+        }
+    }
+
+    /// Returns how many bytes of `buffer` belonged to the chunked body once
+    /// the last chunk and the trailers have been read, `None` if more input
+    /// is needed.
+    pub fn decode(&mut self, buffer: &[u8]) -> Result<Option<usize>, ChunkedError>{
+        loop{
+            match self.state{
+                State::Size => {
+                    let line = match self.next_line(buffer)?{
+                        Some(line) => line,
+                        None => return Ok(None)
+                    };
+                    let size = ChunkedDecoder::parse_size(line)?;
+                    if size > self.max_body_size - self.body.len(){
+                        return Err(ChunkedError::TooLarge);
+                    }
+                    self.state = if size == 0 { State::Trailers } else { State::Data(size) };
+                }
+                State::Data(remaining) => {
+                    let available = buffer.len() - self.position;
+                    if available == 0{
+                        return Ok(None);
+                    }
+                    let take = remaining.min(available);
+                    self.body.extend_from_slice(&buffer[self.position..self.position + take]);
+                    self.position += take;
+                    self.state = if take == remaining { State::DataEnd } else { State::Data(remaining - take) };
+                }
+                State::DataEnd => {
+                    let line = match self.next_line(buffer)?{
+                        Some(line) => line,
+                        None => return Ok(None)
+                    };
+                    if !line.is_empty(){
+                        return Err(ChunkedError::Malformed("chunk data longer than its size"));
+                    }
+                    self.state = State::Size;
+                }
+                State::Trailers => {
+                    let start = self.position;
+                    let line = match self.next_line(buffer)?{
+                        Some(line) => line,
+                        None => return Ok(None)
+                    };
+                    if line.is_empty(){
+                        self.state = State::Done;
+                        continue;
+                    }
+                    let line = line.to_vec();
+                    self.trailer_size += self.position - start;
+                    if self.trailer_size > MAX_TRAILER_SIZE{
+                        return Err(ChunkedError::TooLarge);
+                    }
+                    self.add_trailer(&line)?;
+                }
+                State::Done => return Ok(Some(self.position))
+            }
+        }
+    }
+
+    pub fn is_done(&self) -> bool{
+        self.state == State::Done
+    }
+
+    pub fn into_parts(self) -> (Vec<u8>, HashMap<String, String>){
+        (self.body, self.trailers)
+    }
+
+    // Next line without its terminator; a bare LF is tolerated
+    fn next_line<'b>(&mut self, buffer: &'b [u8]) -> Result<Option<&'b [u8]>, ChunkedError>{
+        let rest = &buffer[self.position..];
+        let end = match rest.iter().position(|&b| b == b'\n'){
+            Some(end) => end,
+            None => {
+                if rest.len() > MAX_LINE_LENGTH{
+                    return Err(ChunkedError::Malformed("line too long"));
+                }
+                return Ok(None)
+            }
+        };
+        if end > MAX_LINE_LENGTH{
+            return Err(ChunkedError::Malformed("line too long"));
+        }
+        self.position += end + 1;
+        let line = &rest[..end];
+        Ok(Some(line.strip_suffix(b"\r").unwrap_or(line)))
+    }
+
+    fn parse_size(line: &[u8]) -> Result<usize, ChunkedError>{
+        // Chunk extensions are allowed after `;` and ignored
+        let size = match line.iter().position(|&b| b == b';'){
+            Some(index) => &line[..index],
+            None => line
+        };
+        let size = std::str::from_utf8(size)
+            .map_err(|_| ChunkedError::Malformed("invalid chunk size"))?
+            .trim_matches(|c| c == ' ' || c == '\t');
+        if size.is_empty() || size.len() > 16 || !size.bytes().all(|b| b.is_ascii_hexdigit()){
+            return Err(ChunkedError::Malformed("invalid chunk size"));
+        }
+        let size = u64::from_str_radix(size, 16)
+            .map_err(|_| ChunkedError::Malformed("invalid chunk size"))?;
+        usize::try_from(size).map_err(|_| ChunkedError::TooLarge)
+    }
+
+    fn add_trailer(&mut self, line: &[u8]) -> Result<(), ChunkedError>{
+        let line = std::str::from_utf8(line)
+            .map_err(|_| ChunkedError::Malformed("invalid trailer"))?;
+        let (name, value) = line.split_once(':')
+            .ok_or(ChunkedError::Malformed("invalid trailer"))?;
+        if name.is_empty() || name.contains(|c: char| c.is_whitespace()){
+            return Err(ChunkedError::Malformed("invalid trailer"));
+        }
+        if FORBIDDEN_TRAILERS.contains(&name.to_ascii_lowercase().as_str()){
+            return Ok(());
+        }
+        self.trailers.entry(name.to_string()).or_insert_with(|| value.trim().to_string());
+        Ok(())
+    }
+}
@@ -0,0 +1,255 @@
+use std::io::{self, Read, Write};
+
+use flate2::write::GzEncoder;
+use futures::StreamExt;
+
+use super::parser::Parser;
+use super::http_status::HttpStatus;
+use super::response::{Body, BodyStream, Response};
+
+/// Buffered bodies smaller than this are sent as they are.
+pub const MIN_SIZE: usize = 1024;
+
+// Dynamic content favours speed over the best ratio
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+const ZSTD_LEVEL: i32 = 3;
+
+/// A content coding the server can produce, in order of preference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding{
+    Brotli,
+    Zstd,
+    Gzip
+}
+
+impl Encoding{
+    const SUPPORTED: [Encoding; 3] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+    pub fn name(&self) -> &'static str{
+        match self{
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip"
+        }
+    }
+
+    /// Picks the coding with the highest q-value in an `Accept-Encoding`
+    /// header, ties going to the order of preference.
+    pub fn negotiate(accept_encoding: &str) -> Option<Encoding>{
+        let mut wildcard = None;
+        let mut weights : Vec<(&str, f32)> = Vec::new();
+        for item in accept_encoding.split(',').map(str::trim).filter(|item| !item.is_empty()){
+            let mut params = item.split(';').map(str::trim);
+            let coding = params.next().unwrap_or_default();
+            let q = params
+                .find_map(|param| param.strip_prefix("q=").or_else(|| param.strip_prefix("Q=")))
+                .map(|q| q.trim().parse::<f32>().unwrap_or(0.0))
+                .unwrap_or(1.0);
+            if coding == "*"{
+                wildcard = Some(q);
+            }
+            else{
+                weights.push((coding, q));
+            }
+        }
+
+        let mut best : Option<(Encoding, f32)> = None;
+        for encoding in Encoding::SUPPORTED{
+            let q = weights.iter()
+                .find(|(coding, _)| coding.eq_ignore_ascii_case(encoding.name()) ||
+                    (encoding == Encoding::Gzip && coding.eq_ignore_ascii_case("x-gzip")))
+                .map(|(_, q)| *q)
+                .or(wildcard)
+                .unwrap_or(0.0);
+            if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q){
+                best = Some((encoding, q));
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+
+    pub fn compress(&self, body: &[u8]) -> io::Result<Vec<u8>>{
+        Encoder::new(*self)?.compress(body)
+    }
+
+    /// Compresses a body stream. Every chunk is flushed through the encoder,
+    /// so streamed responses such as event streams aren't held back.
+    pub fn compress_stream(&self, stream: BodyStream) -> io::Result<BodyStream>{
+        Ok(Encoder::new(*self)?.into_stream(stream))
+    }
+}
+
+enum Encoder{
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Gzip(GzEncoder<Vec<u8>>)
+}
+
+impl Encoder{
+    fn new(encoding: Encoding) -> io::Result<Self>{
+        Ok(match encoding{
+            Encoding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, BROTLI_WINDOW))),
+            Encoding::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(Vec::new(), ZSTD_LEVEL)?),
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), flate2::Compression::default()))
+        })
+    }
+
+    // Returns whatever the encoder has produced so far
+    fn write(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>>{
+        let output = match self{
+            Encoder::Brotli(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.flush()?;
+                encoder.get_mut()
+            }
+            Encoder::Zstd(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.flush()?;
+                encoder.get_mut()
+            }
+            Encoder::Gzip(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.flush()?;
+                encoder.get_mut()
+            }
+        };
+        Ok(std::mem::take(output))
+    }
+
+    fn compress(mut self, body: &[u8]) -> io::Result<Vec<u8>>{
+        let mut compressed = self.write(body)?;
+        compressed.extend(self.finish()?);
+        Ok(compressed)
+    }
+
+    fn into_stream(self, stream: BodyStream) -> BodyStream{
+        Box::pin(futures::stream::unfold(Some((stream, self)), |state| async move {
+            let (mut stream, mut encoder) = state?;
+            loop{
+                match stream.next().await{
+                    Some(Ok(chunk)) => match encoder.write(&chunk){
+                        Ok(compressed) if compressed.is_empty() => continue,
+                        Ok(compressed) => return Some((Ok(compressed), Some((stream, encoder)))),
+                        Err(err) => return Some((Err(err), None))
+                    },
+                    Some(Err(err)) => return Some((Err(err), None)),
+                    None => return Some((encoder.finish(), None))
+                }
+            }
+        }))
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>>{
+        match self{
+            Encoder::Brotli(encoder) => Ok(encoder.into_inner()),
+            Encoder::Zstd(encoder) => encoder.finish(),
+            Encoder::Gzip(encoder) => encoder.finish()
+        }
+    }
+}
+
+/// Undoes the `Content-Encoding` of a request body, the last coding listed
+/// first. Codings the server can't decode are refused with 415, and bodies
+/// that decode to more than `max_size` bytes with 413.
+pub fn decompress(content_encoding: &str, mut body: Vec<u8>, max_size: usize) -> Result<Vec<u8>, HttpStatus>{
+    let codings : Vec<String> = content_encoding.split(',')
+        .map(|coding| coding.trim().to_ascii_lowercase())
+        .filter(|coding| !coding.is_empty() && coding != "identity")
+        .collect();
+    if codings.iter().any(|coding| !["gzip", "x-gzip", "deflate", "br", "zstd"].contains(&coding.as_str())){
+        return Err(HttpStatus::UnsupportedMediaType);
+    }
+    if body.is_empty(){
+        return Ok(body);
+    }
+    for coding in codings.iter().rev(){
+        let input = body.as_slice();
+        body = match coding.as_str(){
+            "gzip" | "x-gzip" => read_capped(flate2::read::MultiGzDecoder::new(input), max_size)?,
+            // `deflate` in HTTP is the zlib format
+            "deflate" => read_capped(flate2::read::ZlibDecoder::new(input), max_size)?,
+            "br" => read_capped(brotli::Decompressor::new(input, 4096), max_size)?,
+            _ => read_capped(zstd::stream::read::Decoder::new(input).map_err(|_| HttpStatus::BadRequest)?, max_size)?
+        };
+    }
+    Ok(body)
+}
+
+// Stops reading one byte past the cap, so a zip bomb is never inflated in full
+fn read_capped(decoder: impl Read, max_size: usize) -> Result<Vec<u8>, HttpStatus>{
+    let mut body = Vec::new();
+    decoder.take(max_size as u64 + 1).read_to_end(&mut body).map_err(|_| HttpStatus::BadRequest)?;
+    if body.len() > max_size{
+        return Err(HttpStatus::PayloadTooLarge);
+    }
+    Ok(body)
+}
+
+// Media types that are already compressed gain nothing
+fn is_compressible(content_type: &str) -> bool{
+    let media_type = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    media_type.starts_with("text/") ||
+        media_type.ends_with("/json") || media_type.ends_with("+json") ||
+        media_type.ends_with("/xml") || media_type.ends_with("+xml") ||
+        media_type.ends_with("/javascript") || media_type == "application/wasm"
+}
+
+/// Compresses the response body with the best coding the request accepts.
+/// Partial and bodiless responses, already encoded bodies, media types that
+/// don't compress and small buffered bodies are left alone.
+pub(crate) fn apply(request: &Parser, resp: &mut Response){
+    let code = resp.status.split_whitespace().next().unwrap_or_default();
+    if code.starts_with('1') || code.starts_with('3') || code == "204" || code == "206"{
+        return;
+    }
+    if resp.header("Content-Encoding").is_some() || !resp.header("Content-Type").is_some_and(is_compressible){
+        return;
+    }
+    if matches!(&resp.body, Body::Full(body) if body.len() < MIN_SIZE){
+        return;
+    }
+
+    match resp.headers.iter_mut().find(|(name, _)| name.eq_ignore_ascii_case("Vary")){
+        Some((_, vary)) if vary.split(',').any(|value| value.trim().eq_ignore_ascii_case("Accept-Encoding") || value.trim() == "*") => {}
+        Some((_, vary)) => vary.push_str(", Accept-Encoding"),
+        None => resp.headers.push(("Vary".to_string(), "Accept-Encoding".to_string()))
+    }
+
+    let encoding = match request.header_value("Accept-Encoding").and_then(|accept| Encoding::negotiate(accept)){
+        Some(encoding) => encoding,
+        None => return
+    };
+    // Built first, so a failure leaves the response as it was
+    let encoder = match Encoder::new(encoding){
+        Ok(encoder) => encoder,
+        Err(err) => {
+            println!("Error compressing response {err}");
+            return
+        }
+    };
+    resp.body = match std::mem::take(&mut resp.body){
+        Body::Full(body) => match encoder.compress(&body){
+            Ok(compressed) => {
+                resp.set_header("Content-Length", compressed.len().to_string());
+                Body::Full(compressed)
+            }
+            Err(err) => {
+                println!("Error compressing response {err}");
+                resp.body = Body::Full(body);
+                return
+            }
+        },
+        Body::Stream(stream) => {
+            resp.headers.retain(|(name, _)| !name.eq_ignore_ascii_case("Content-Length"));
+            Body::Stream(encoder.into_stream(stream))
+        }
+    };
+    resp.set_header("Content-Encoding", encoding.name());
+    // The encoded bytes differ, but still match the same validators weakly
+    if let Some((_, etag)) = resp.headers.iter_mut().find(|(name, _)| name.eq_ignore_ascii_case("ETag")){
+        if !etag.starts_with("W/"){
+            etag.insert_str(0, "W/");
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sha1::{Digest, Sha1};
+
+use super::parser::Parser;
+use super::response::{Body, Response};
+use super::http_status::HttpStatus;
+
+// Kept on a 304, everything else describes the body that isn't sent
+const NOT_MODIFIED_HEADERS: [&str; 7] = ["cache-control", "content-location", "date", "etag", "expires", "last-modified", "vary"];
+
+/// What the conditional headers of a request ask for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precondition{
+    Proceed,
+    NotModified,
+    Failed
+}
+
+/// Strong ETag for a body held in memory.
+pub fn etag_for_bytes(body: &[u8]) -> String{
+    let digest = Sha1::digest(body);
+    let hex : String = digest[..10].iter().map(|byte| format!("{byte:02x}")).collect();
+    format!("\"{hex}\"")
+}
+
+/// ETag for a file from its modification time and size, which changes
+/// whenever the file is written without reading it.
+pub fn etag_for_file(modified: SystemTime, len: u64) -> String{
+    let modified = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("\"{:x}.{:x}-{:x}\"", modified.as_secs(), modified.subsec_nanos(), len)
+}
+
+pub fn format_date(time: SystemTime) -> String{
+    httpdate::fmt_http_date(time)
+}
+
+/// Evaluates `If-Match`, `If-Unmodified-Since`, `If-None-Match` and
+/// `If-Modified-Since` in the order RFC 9110 gives them, against the
+/// validators of the current representation.
+pub fn evaluate(request: &Parser, etag: Option<&str>, last_modified: Option<SystemTime>) -> Precondition{
+    let safe = request.method == "GET" || request.method == "HEAD";
+    let modified_secs = last_modified.map(seconds);
+
+    if let Some(if_match) = request.header_value("If-Match"){
+        if !matches_any(if_match, etag, true){
+            return Precondition::Failed;
+        }
+    }
+    else if let (Some(since), Some(modified)) = (request.header_value("If-Unmodified-Since"), modified_secs){
+        if parse_date(since).is_some_and(|since| modified > since){
+            return Precondition::Failed;
+        }
+    }
+
+    if let Some(if_none_match) = request.header_value("If-None-Match"){
+        if matches_any(if_none_match, etag, false){
+            return if safe { Precondition::NotModified } else { Precondition::Failed };
+        }
+    }
+    else if let (true, Some(since), Some(modified)) = (safe, request.header_value("If-Modified-Since"), modified_secs){
+        if parse_date(since).is_some_and(|since| modified <= since){
+            return Precondition::NotModified;
+        }
+    }
+
+    Precondition::Proceed
+}
+
+/// Adds an ETag to successful GET and HEAD responses held in memory, then
+/// turns them into a 304 or 412 when the request's preconditions say so.
+pub(crate) fn apply(request: &Parser, resp: &mut Response){
+    if (request.method != "GET" && request.method != "HEAD") || !resp.status.starts_with("200"){
+        return;
+    }
+    if resp.header("ETag").is_none(){
+        if let Some(body) = resp.body.as_bytes(){
+            let etag = etag_for_bytes(body);
+            resp.headers.push(("ETag".to_string(), etag));
+        }
+    }
+
+    let last_modified = resp.header("Last-Modified").and_then(|date| httpdate::parse_http_date(date).ok());
+    match evaluate(request, resp.header("ETag"), last_modified){
+        Precondition::Proceed => {}
+        Precondition::NotModified => {
+            resp.status = HttpStatus::NotModified.to_string();
+            resp.headers.retain(|(name, _)| NOT_MODIFIED_HEADERS.contains(&name.to_ascii_lowercase().as_str()));
+            resp.body = Body::empty();
+        }
+        Precondition::Failed => {
+            resp.status = HttpStatus::PreconditionFailed.to_string();
+            resp.headers = vec![("Content-Length".to_string(), "0".to_string())];
+            resp.body = Body::empty();
+        }
+    }
+}
+
+// `If-Match` compares strongly, `If-None-Match` weakly. `*` matches any
+// current representation, with or without an ETag
+fn matches_any(header: &str, etag: Option<&str>, strong: bool) -> bool{
+    if header.trim() == "*"{
+        return true;
+    }
+    let etag = match etag{
+        Some(etag) => etag,
+        None => return false
+    };
+    header.split(',').map(str::trim).any(|candidate| {
+        if strong{
+            !candidate.starts_with("W/") && !etag.starts_with("W/") && candidate == etag
+        }
+        else{
+            candidate.trim_start_matches("W/") == etag.trim_start_matches("W/")
+        }
+    })
+}
+
+// HTTP dates only have whole seconds
+fn seconds(time: SystemTime) -> u64{
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn parse_date(date: &str) -> Option<u64>{
+    httpdate::parse_http_date(date.trim()).ok().map(seconds)
+}
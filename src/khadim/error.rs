@@ -0,0 +1,52 @@
+use std::error::Error;
+use std::fmt;
+
+use super::extract::Rejection;
+use super::http_status::HttpStatus;
+
+type Source = Box<dyn Error + Send + Sync>;
+
+/// Why a handler failed. `?` turns any error into a 500 that keeps the
+/// original as its source, and a `Rejection` into its own status.
+#[derive(Debug)]
+pub struct HandlerError{
+    pub status: HttpStatus,
+    pub message: String,
+    source: Option<Source>
+}
+
+impl HandlerError{
+    pub fn new(status: HttpStatus, message: impl Into<String>) -> Self{
+        HandlerError{status, message: message.into(), source: None}
+    }
+
+    pub fn with_source(mut self, source: impl Into<Source>) -> Self{
+        self.source = Some(source.into());
+        self
+    }
+
+    /// The error that caused this one, if any.
+    pub fn source(&self) -> Option<&(dyn Error + Send + Sync + 'static)>{
+        self.source.as_deref()
+    }
+}
+
+impl fmt::Display for HandlerError{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.status, self.message)
+    }
+}
+
+// Not `std::error::Error` itself, which would conflict with this impl
+impl<E: Into<Source>> From<E> for HandlerError{
+    fn from(error: E) -> Self{
+        match error.into().downcast::<Rejection>(){
+            Ok(rejection) => HandlerError::new(rejection.status, rejection.message),
+            Err(error) => HandlerError{
+                status: HttpStatus::InternalServerError,
+                message: error.to_string(),
+                source: Some(error)
+            }
+        }
+    }
+}
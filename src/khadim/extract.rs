@@ -0,0 +1,164 @@
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use super::response::Request;
+use super::http_status::HttpStatus;
+
+pub use http::HeaderMap;
+
+/// Why an argument could not be extracted. Returned from an `#[api_callback]`
+/// it becomes a response with `status` and `message` as the body.
+#[derive(Debug)]
+pub struct Rejection{
+    pub status: HttpStatus,
+    pub message: String
+}
+
+impl Rejection{
+    pub fn new(status: HttpStatus, message: impl Into<String>) -> Self{
+        Rejection{status, message: message.into()}
+    }
+}
+
+impl fmt::Display for Rejection{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for Rejection{}
+
+/// Builds a handler argument from the request. Arguments of an
+/// `#[api_callback]` other than `Request` and `ResponseWriter` are extracted
+/// through this trait.
+pub trait FromRequest: Sized{
+    fn from_request(request: &Request) -> Result<Self, Rejection>;
+}
+
+/// State registered with `Server::with_state`.
+#[derive(Debug)]
+pub struct State<T>(pub Arc<T>);
+
+/// Path parameters, e.g. `Path<u32>` for `/users/:id` or a struct with a
+/// field per parameter.
+#[derive(Debug)]
+pub struct Path<T>(pub T);
+
+#[derive(Debug)]
+pub struct Query<T>(pub T);
+
+/// A JSON body, requires `Content-Type: application/json`.
+#[derive(Debug)]
+pub struct Json<T>(pub T);
+
+/// A form body, requires `Content-Type: application/x-www-form-urlencoded`.
+#[derive(Debug)]
+pub struct Form<T>(pub T);
+
+impl<T> Deref for State<T>{
+    type Target = T;
+
+    fn deref(&self) -> &T{
+        &self.0
+    }
+}
+
+impl<T: Send + Sync + 'static> FromRequest for State<T>{
+    fn from_request(request: &Request) -> Result<Self, Rejection>{
+        request.state::<T>().map(State).ok_or_else(|| {
+            Rejection::new(HttpStatus::InternalServerError, format!("No state of type {} registered", std::any::type_name::<T>()))
+        })
+    }
+}
+
+impl<T: DeserializeOwned> FromRequest for Path<T>{
+    fn from_request(request: &Request) -> Result<Self, Rejection>{
+        let pairs : Vec<(&String, &String)> = request.params.iter().collect();
+        if let Ok(value) = deserialize_pairs(&pairs){
+            return Ok(Path(value));
+        }
+        // A lone parameter can be taken as a plain value
+        if let [(_, value)] = pairs.as_slice(){
+            if let Some(value) = deserialize_value(value){
+                return Ok(Path(value));
+            }
+        }
+        Err(Rejection::new(HttpStatus::BadRequest, "Invalid path parameters"))
+    }
+}
+
+impl<T: DeserializeOwned> FromRequest for Query<T>{
+    fn from_request(request: &Request) -> Result<Self, Rejection>{
+        let pairs : Vec<(&String, &String)> = match &request.request.query_params{
+            Some(query_params) => query_params.iter().collect(),
+            None => Vec::new()
+        };
+        deserialize_pairs(&pairs)
+            .map(Query)
+            .map_err(|err| Rejection::new(HttpStatus::BadRequest, format!("Invalid query string: {}", err)))
+    }
+}
+
+impl<T: DeserializeOwned> FromRequest for Json<T>{
+    fn from_request(request: &Request) -> Result<Self, Rejection>{
+        if !has_content_type(request, |mime| mime == "application/json" || mime.ends_with("+json")){
+            return Err(Rejection::new(HttpStatus::UnsupportedMediaType, "Expected Content-Type: application/json"));
+        }
+        request.json().map(Json).map_err(|err| {
+            // Well formed JSON of the wrong shape is unprocessable, anything else is a bad request
+            let status = match err.classify(){
+                serde_json::error::Category::Data => HttpStatus::UnprocessableEntity,
+                _ => HttpStatus::BadRequest
+            };
+            Rejection::new(status, format!("Invalid JSON body: {}", err))
+        })
+    }
+}
+
+impl<T: DeserializeOwned> FromRequest for Form<T>{
+    fn from_request(request: &Request) -> Result<Self, Rejection>{
+        if !has_content_type(request, |mime| mime == "application/x-www-form-urlencoded"){
+            return Err(Rejection::new(HttpStatus::UnsupportedMediaType, "Expected Content-Type: application/x-www-form-urlencoded"));
+        }
+        serde_urlencoded::from_bytes(request.body())
+            .map(Form)
+            .map_err(|err| Rejection::new(HttpStatus::UnprocessableEntity, format!("Invalid form body: {}", err)))
+    }
+}
+
+impl FromRequest for HeaderMap{
+    fn from_request(request: &Request) -> Result<Self, Rejection>{
+        let mut headers = HeaderMap::new();
+        for (name, value) in &request.request.header{
+            let name = http::HeaderName::from_bytes(name.as_bytes());
+            let value = http::HeaderValue::from_str(value);
+            if let (Ok(name), Ok(value)) = (name, value){
+                headers.append(name, value);
+            }
+        }
+        Ok(headers)
+    }
+}
+
+fn deserialize_pairs<T: DeserializeOwned>(pairs: &[(&String, &String)]) -> Result<T, serde_urlencoded::de::Error>{
+    let encoded = url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(pairs)
+        .finish();
+    serde_urlencoded::from_str(&encoded)
+}
+
+// Numbers and booleans are read as JSON, anything else as a string
+fn deserialize_value<T: DeserializeOwned>(value: &str) -> Option<T>{
+    serde_json::from_str(value).ok()
+        .or_else(|| serde_json::from_value(Value::String(value.to_string())).ok())
+}
+
+fn has_content_type(request: &Request, accept: impl Fn(&str) -> bool) -> bool{
+    request.request.header_value("Content-Type")
+        .map(|value| value.split(';').next().unwrap_or("").trim().to_ascii_lowercase())
+        .map(|mime| accept(&mime))
+        .unwrap_or(false)
+}
@@ -0,0 +1,47 @@
+use std::fmt;
+
+use super::caller::AsyncReturn;
+use super::error::HandlerError;
+use super::response::{Request, ResponseWriter};
+
+/// Answers a request. Implemented for `#[api_callback]` functions and for
+/// closures, which can capture shared state such as a pool behind an `Arc`.
+pub trait Handler: Send + Sync + 'static {
+    fn call(&self, request: Request, writer: ResponseWriter) -> AsyncReturn;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(Request, ResponseWriter) -> AsyncReturn + Send + Sync + 'static
+{
+    fn call(&self, request: Request, writer: ResponseWriter) -> AsyncReturn {
+        self(request, writer)
+    }
+}
+
+impl fmt::Debug for dyn Handler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Handler")
+    }
+}
+
+/// Answers a request whose handler or middleware failed, with the error that
+/// made it fail.
+pub trait ErrorHandler: Send + Sync + 'static {
+    fn call(&self, request: Request, writer: ResponseWriter, error: HandlerError) -> AsyncReturn;
+}
+
+impl<F> ErrorHandler for F
+where
+    F: Fn(Request, ResponseWriter, HandlerError) -> AsyncReturn + Send + Sync + 'static
+{
+    fn call(&self, request: Request, writer: ResponseWriter, error: HandlerError) -> AsyncReturn {
+        self(request, writer, error)
+    }
+}
+
+impl fmt::Debug for dyn ErrorHandler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ErrorHandler")
+    }
+}
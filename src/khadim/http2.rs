@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use bytes::Bytes;
+use futures::StreamExt;
+use h2::server::SendResponse;
+use h2::{Reason, RecvStream, SendStream};
+use http::{HeaderName, HeaderValue, StatusCode};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::parser::Parser;
+use super::response::{Body, BodyStream, Response};
+use super::router::Router;
+use super::server::{Connection, Server};
+use super::http_status::HttpStatus;
+
+/// First bytes sent by an HTTP/2 client.
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// Connection specific headers are forbidden in HTTP/2 responses
+const HOP_BY_HOP: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+// Replays bytes already read while sniffing for the preface
+struct Prefixed<S>{
+    prefix: Vec<u8>,
+    inner: S
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Prefixed<S>{
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if !self.prefix.is_empty(){
+            let len = self.prefix.len().min(buf.remaining());
+            buf.put_slice(&self.prefix[..len]);
+            self.prefix.drain(..len);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Prefixed<S>{
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Serves an HTTP/2 connection, each stream is routed on its own task.
+/// `buffer` holds bytes already read from the connection.
+pub(crate) async fn serve_connection<S: Connection>(conn: (S, SocketAddr), buffer: Vec<u8>, router: Router, max_body_size: usize){
+    let (stream, address) = conn;
+    let base_address = match stream.tcp().local_addr(){
+        Ok(base_address) => base_address,
+        Err(_) => return
+    };
+    let mut connection = match h2::server::handshake(Prefixed{prefix: buffer, inner: stream}).await{
+        Ok(connection) => connection,
+        Err(err) => {
+            println!("HTTP/2 handshake with {} failed {}", address, err);
+            return
+        }
+    };
+    // Polling `accept` also drives the streams spawned below
+    while let Some(accepted) = connection.accept().await{
+        let (request, respond) = match accepted{
+            Ok(accepted) => accepted,
+            Err(err) => {
+                println!("HTTP/2 connection with {} failed {}", address, err);
+                break
+            }
+        };
+        let router = router.clone();
+        tokio::spawn(async move{
+            if let Err(err) = handle_stream(request, respond, router, (address, base_address), max_body_size).await{
+                println!("HTTP/2 stream error {}", err);
+            }
+        });
+    }
+}
+
+// `addresses` are the peer and the local address
+async fn handle_stream(request: http::Request<RecvStream>, mut respond: SendResponse<Bytes>, router: Router, addresses: (SocketAddr, SocketAddr), max_body_size: usize) -> Result<(), h2::Error>{
+    let (parts, mut body) = request.into_parts();
+    let (address, base_address) = addresses;
+
+    let mut header : HashMap<String, String> = HashMap::new();
+    for (name, value) in parts.headers.iter(){
+        let value = match value.to_str(){
+            Ok(value) => value,
+            Err(_) => continue
+        };
+        match header.get_mut(name.as_str()){
+            // Cookies may be split into several fields in HTTP/2
+            Some(existing) if name == http::header::COOKIE => {
+                existing.push_str("; ");
+                existing.push_str(value);
+            }
+            Some(_) => {},
+            None => {
+                header.insert(name.to_string(), value.to_string());
+            }
+        }
+    }
+    if let Some(authority) = parts.uri.authority(){
+        header.entry("host".to_string()).or_insert_with(|| authority.to_string());
+    }
+
+    let method = parts.method.as_str();
+    let mut trailers = HashMap::new();
+    let body = match method{
+        "GET" | "HEAD" => None,
+        _ => {
+            let declared = header.get("content-length").and_then(|length| length.parse::<usize>().ok());
+            if declared.unwrap_or(0) > max_body_size{
+                return send_status(&mut respond, HttpStatus::PayloadTooLarge);
+            }
+            let mut data = Vec::new();
+            while let Some(chunk) = body.data().await{
+                let chunk = chunk?;
+                let _ = body.flow_control().release_capacity(chunk.len());
+                if data.len() + chunk.len() > max_body_size{
+                    return send_status(&mut respond, HttpStatus::PayloadTooLarge);
+                }
+                data.extend_from_slice(&chunk);
+            }
+            if let Some(fields) = body.trailers().await?{
+                for (name, value) in fields.iter(){
+                    if let Ok(value) = value.to_str(){
+                        trailers.entry(name.to_string()).or_insert_with(|| value.to_string());
+                    }
+                }
+            }
+            Some(data)
+        }
+    };
+
+    let target = parts.uri.path_and_query().map(|target| target.as_str()).unwrap_or("/");
+    let mut parser = match Parser::from_parts(method, target, 1, header, body, &base_address){
+        Ok(parser) => parser,
+        Err(_) => return send_status(&mut respond, HttpStatus::BadRequest)
+    };
+    parser.trailers = trailers;
+    if let Err(status) = parser.decompress_body(max_body_size){
+        return send_status(&mut respond, status);
+    }
+
+    let head_only = parser.method == "HEAD";
+    let resp = Server::route(&router, parser, address).await;
+    send_response(&mut respond, resp, head_only).await
+}
+
+fn status_code(status: &str) -> StatusCode{
+    status.split_whitespace().next()
+        .and_then(|code| code.parse::<u16>().ok())
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+fn send_status(respond: &mut SendResponse<Bytes>, status: HttpStatus) -> Result<(), h2::Error>{
+    let mut head = http::Response::new(());
+    *head.status_mut() = status_code(&status.to_string());
+    respond.send_response(head, true)?;
+    Ok(())
+}
+
+async fn send_response(respond: &mut SendResponse<Bytes>, mut resp: Response, head_only: bool) -> Result<(), h2::Error>{
+    let mut head = http::Response::new(());
+    *head.status_mut() = status_code(&resp.status);
+    for (name, value) in &resp.headers{
+        if HOP_BY_HOP.contains(&name.to_ascii_lowercase().as_str()){
+            continue
+        }
+        match (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)){
+            (Ok(name), Ok(value)) => {
+                head.headers_mut().append(name, value);
+            }
+            _ => println!("Dropping invalid header {}", name)
+        }
+    }
+
+    let body = std::mem::take(&mut resp.body);
+    let empty = head_only || matches!(&body, Body::Full(bytes) if bytes.is_empty());
+    let mut send = respond.send_response(head, empty)?;
+    if empty{
+        return Ok(());
+    }
+    match body{
+        Body::Full(bytes) => send_data(&mut send, Bytes::from(bytes), true).await,
+        Body::Stream(stream) => send_stream(&mut send, stream).await
+    }
+}
+
+// Waits for flow control window before each frame, which is the backpressure
+async fn send_data(send: &mut SendStream<Bytes>, mut data: Bytes, end_of_stream: bool) -> Result<(), h2::Error>{
+    while !data.is_empty(){
+        send.reserve_capacity(data.len());
+        let capacity = match futures::future::poll_fn(|cx| send.poll_capacity(cx)).await{
+            Some(capacity) => capacity?,
+            None => return Err(h2::Error::from(Reason::CANCEL))
+        };
+        if capacity == 0{
+            continue
+        }
+        let chunk = data.split_to(capacity.min(data.len()));
+        send.send_data(chunk, end_of_stream && data.is_empty())?;
+    }
+    Ok(())
+}
+
+// A reset from the client stops the body stream from being polled again
+async fn send_stream(send: &mut SendStream<Bytes>, mut body: BodyStream) -> Result<(), h2::Error>{
+    loop{
+        let next = tokio::select! {
+            next = body.next() => next,
+            reason = futures::future::poll_fn(|cx| send.poll_reset(cx)) => {
+                return Err(h2::Error::from(reason?))
+            }
+        };
+        match next{
+            Some(Ok(chunk)) => {
+                if !chunk.is_empty(){
+                    send_data(send, Bytes::from(chunk), false).await?;
+                }
+            }
+            Some(Err(err)) => {
+                println!("Error while streaming response {err}");
+                send.send_reset(Reason::INTERNAL_ERROR);
+                return Ok(())
+            }
+            None => break
+        }
+    }
+    send.send_data(Bytes::new(), true)
+}
@@ -32,6 +32,7 @@ pub enum HttpHeader {
     Referer(String),
     TE(String),
     AccessControlAllowOrigin(String),
+    AcceptRanges(String),
     Age(String),
     Allow(String),
     ContentEncoding(String),
@@ -84,6 +85,7 @@ impl HttpHeader {
             HttpHeader::Referer(value) => ("Referer", value),
             HttpHeader::TE(value) => ("TE", value),
             HttpHeader::AccessControlAllowOrigin(value) => ("Access-Control-Allow-Origin", value),
+            HttpHeader::AcceptRanges(value) => ("Accept-Ranges", value),
             HttpHeader::Age(value) => ("Age", value),
             HttpHeader::Allow(value) => ("Allow", value),
             HttpHeader::ContentEncoding(value) => ("Content-Encoding", value),
@@ -1,21 +1,64 @@
 use std::fmt;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum HttpMethod {
     GET,
+    HEAD,
     POST,
     PUT,
     DELETE,
+    CONNECT,
+    OPTIONS,
+    TRACE,
+    PATCH,
+    // Any other token, e.g. WebDAV's PROPFIND
+    Extension(String),
+}
+
+impl HttpMethod {
+    pub fn parse(method: &str) -> Option<HttpMethod> {
+        let parsed = match method {
+            "GET" => HttpMethod::GET,
+            "HEAD" => HttpMethod::HEAD,
+            "POST" => HttpMethod::POST,
+            "PUT" => HttpMethod::PUT,
+            "DELETE" => HttpMethod::DELETE,
+            "CONNECT" => HttpMethod::CONNECT,
+            "OPTIONS" => HttpMethod::OPTIONS,
+            "TRACE" => HttpMethod::TRACE,
+            "PATCH" => HttpMethod::PATCH,
+            _ => {
+                if !HttpMethod::is_token(method) {
+                    return None;
+                }
+                HttpMethod::Extension(method.to_string())
+            }
+        };
+        Some(parsed)
+    }
+
+    // RFC 9110 section 5.6.2
+    fn is_token(method: &str) -> bool {
+        !method.is_empty() && method.bytes().all(|b| {
+            b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+        })
+    }
 }
 
 impl fmt::Display for HttpMethod {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let response = match self {
             HttpMethod::GET => "GET",
+            HttpMethod::HEAD => "HEAD",
             HttpMethod::POST => "POST",
             HttpMethod::PUT => "PUT",
             HttpMethod::DELETE => "DELETE",
+            HttpMethod::CONNECT => "CONNECT",
+            HttpMethod::OPTIONS => "OPTIONS",
+            HttpMethod::TRACE => "TRACE",
+            HttpMethod::PATCH => "PATCH",
+            HttpMethod::Extension(method) => method,
         };
         write!(f, "{}", response)
     }
-}
\ No newline at end of file
+}
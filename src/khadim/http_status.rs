@@ -1,7 +1,7 @@
 #![allow(dead_code)]
 use std::fmt;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum HttpStatus {
     // 1xx: Informational
     Continue,                    // 100
@@ -47,7 +47,9 @@ pub enum HttpStatus {
     ExpectationFailed,           // 417
     ImATeapot,                   // 418
     UnprocessableEntity,         // 422
+    UpgradeRequired,             // 426
     TooManyRequests,             // 429
+    RequestHeaderFieldsTooLarge, // 431
 
     // 5xx: Server Error
     InternalServerError,         // 500
@@ -59,6 +61,14 @@ pub enum HttpStatus {
 }
 
 impl HttpStatus {
+    pub fn code(&self) -> u16 {
+        // The reason phrase always follows the three digit code
+        self.to_string()[..3].parse().unwrap()
+    }
+
+    pub fn is_server_error(&self) -> bool {
+        self.code() >= 500
+    }
 }
 
 impl fmt::Display for HttpStatus {
@@ -101,7 +111,9 @@ impl fmt::Display for HttpStatus {
             HttpStatus::ExpectationFailed => "417 Expectation Failed",
             HttpStatus::ImATeapot => "418 I'm a teapot",
             HttpStatus::UnprocessableEntity => "422 Unprocessable Entity",
+            HttpStatus::UpgradeRequired => "426 Upgrade Required",
             HttpStatus::TooManyRequests => "429 Too Many Requests",
+            HttpStatus::RequestHeaderFieldsTooLarge => "431 Request Header Fields Too Large",
             HttpStatus::InternalServerError => "500 Internal Server Error",
             HttpStatus::NotImplemented => "501 Not Implemented",
             HttpStatus::BadGateway => "502 Bad Gateway",
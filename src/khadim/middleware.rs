@@ -0,0 +1,53 @@
+use std::fmt;
+use std::sync::Arc;
+
+use super::caller::AsyncReturn;
+use super::handler::Handler;
+use super::response::{Request, ResponseWriter};
+
+/// Runs around the handler. Call `next.run` to continue down the stack, or
+/// return a response without calling it to stop the request there. Awaiting
+/// the future from `next.run` gives the response before it is sent.
+pub trait Middleware: Send + Sync + 'static {
+    fn handle(&self, request: Request, writer: ResponseWriter, next: Next) -> AsyncReturn;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(Request, ResponseWriter, Next) -> AsyncReturn + Send + Sync + 'static
+{
+    fn handle(&self, request: Request, writer: ResponseWriter, next: Next) -> AsyncReturn {
+        self(request, writer, next)
+    }
+}
+
+impl fmt::Debug for dyn Middleware {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Middleware")
+    }
+}
+
+pub type MiddlewareStack = Arc<Vec<Arc<dyn Middleware>>>;
+
+/// The rest of the middleware stack followed by the handler.
+pub struct Next{
+    stack: MiddlewareStack,
+    index: usize,
+    handler: Arc<dyn Handler>
+}
+
+impl Next{
+    pub fn new(stack: MiddlewareStack, handler: Arc<dyn Handler>) -> Self{
+        Next{stack, index: 0, handler}
+    }
+
+    pub fn run(self, request: Request, writer: ResponseWriter) -> AsyncReturn{
+        match self.stack.get(self.index).cloned(){
+            Some(middleware) => {
+                let next = Next{index: self.index + 1, ..self};
+                middleware.handle(request, writer, next)
+            }
+            None => self.handler.call(request, writer)
+        }
+    }
+}
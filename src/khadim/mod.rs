@@ -1,10 +1,22 @@
 pub mod server;
 pub mod router;
 pub mod parser;
+pub mod chunked;
 pub mod response;
 pub mod tests;
 pub mod http_status;
 pub mod caller;
+pub mod handler;
+pub mod error;
+pub mod middleware;
+pub mod extract;
 pub mod http_header;
 pub mod http_method;
-pub mod tls;
\ No newline at end of file
+pub mod tls;
+pub mod sse;
+pub mod static_files;
+pub mod conditional;
+pub mod range;
+pub mod compression;
+pub mod websocket;
+pub mod http2;
\ No newline at end of file
@@ -2,14 +2,19 @@ use std::{collections::HashMap, error::Error, net::SocketAddr};
 use httparse::Request;
 use url::Url;
 use std::str;
+use super::compression;
+use super::http_status::HttpStatus;
 
 #[derive(Debug, Clone)]
 pub struct Parser{
     pub method: String,
     pub path: String,
+    // Minor version, 0 for HTTP/1.0 and 1 for HTTP/1.1 (HTTP/2 counts as 1)
+    pub version: u8,
     pub header: HashMap<String, String>,
     pub query_params: Option<HashMap<String, String>>,
-    pub body: Option<String>
+    pub body: Option<Vec<u8>>,
+    pub trailers: HashMap<String, String>
 }
 
 impl Parser{
@@ -23,6 +28,7 @@ impl Parser{
             Some(p) => p.to_string(),
             None => return Err("No path found".into())
         };
+        let version = payload.version.unwrap_or(1);
         let body = Parser::parse_body(&payload, buffer, parsed_len);
         let url_qp_tup = Parser::parse_url_and_get_query_params(&path, base_address);
 
@@ -38,17 +44,110 @@ impl Parser{
 
         let mut header_map : HashMap<String, String>= HashMap::new();
         for header in headers{
-            if header_map.contains_key(header.name){
+            // obs-text values aren't UTF-8, and nothing here reads them
+            let value = match std::str::from_utf8(header.value){
+                Ok(value) => value,
+                Err(_) => continue
+            };
+            if header.name.eq_ignore_ascii_case("Content-Length"){
+                // Every line is kept so `content_length` can spot lines that disagree
+                match header_map.iter_mut().find(|(key, _)| key.eq_ignore_ascii_case("Content-Length")){
+                    Some((_, lengths)) => {
+                        lengths.push_str(", ");
+                        lengths.push_str(value);
+                    }
+                    None => {
+                        header_map.insert(header.name.to_string(), value.to_string());
+                    }
+                }
             }
-            else{
-                let value = std::str::from_utf8(header.value).unwrap();
+            else if !header_map.contains_key(header.name){
                 header_map.insert(header.name.to_string(), value.to_string());
             }
         }
 
         let header = header_map;
 
-        Ok(Parser { method, path, header , query_params, body})
+        Ok(Parser { method, path, version, header , query_params, body, trailers: HashMap::new()})
+    }
+
+    /// Builds a request that was not parsed from HTTP/1 text, e.g. an HTTP/2
+    /// stream. `target` is the path with its query string.
+    pub fn from_parts(method: &str, target: &str, version: u8, header: HashMap<String, String>, body: Option<Vec<u8>>, base_address: &SocketAddr) -> Result<Self, Box<dyn Error>>{
+        let (path, query_params) = Parser::parse_url_and_get_query_params(target, base_address)?;
+        Ok(Parser {
+            method: method.to_string(),
+            path,
+            version,
+            header,
+            query_params: Some(query_params),
+            body,
+            trailers: HashMap::new()
+        })
+    }
+
+    /// Copy of the request line and headers, without the body.
+    pub fn without_body(&self) -> Self{
+        Parser{
+            method: self.method.clone(),
+            path: self.path.clone(),
+            version: self.version,
+            header: self.header.clone(),
+            query_params: self.query_params.clone(),
+            body: None,
+            trailers: self.trailers.clone()
+        }
+    }
+
+    /// Header lookup ignoring the case of the header name.
+    pub fn header_value(&self, name: &str) -> Option<&String>{
+        self.header.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    /// True when the body is sent with `Transfer-Encoding: chunked`, which
+    /// has to be the last coding applied.
+    pub fn is_chunked(&self) -> bool{
+        match self.header_value("Transfer-Encoding"){
+            Some(encoding) => {
+                encoding.rsplit(',').next()
+                    .map(|last| last.trim().eq_ignore_ascii_case("chunked"))
+                    .unwrap_or(false)
+            }
+            None => false
+        }
+    }
+
+    /// Value of `Content-Length`, `Err` when it is not a valid length or
+    /// repeated with different values, in one field or on several lines.
+    pub fn content_length(&self) -> Result<Option<usize>, Box<dyn Error>>{
+        let value = match self.header_value("Content-Length"){
+            Some(value) => value,
+            None => return Ok(None)
+        };
+        let mut lengths = value.split(',').map(|length| length.trim());
+        let first = lengths.next().unwrap_or("");
+        if first.is_empty() || !first.bytes().all(|b| b.is_ascii_digit()) || lengths.any(|length| length != first){
+            return Err(format!("Invalid Content-Length {}", value).into());
+        }
+        Ok(Some(first.parse::<usize>()?))
+    }
+
+    /// Decodes a body sent with `Content-Encoding`, so handlers only see the
+    /// plain bytes. The header is dropped and `Content-Length` updated to match.
+    pub fn decompress_body(&mut self, max_body_size: usize) -> Result<(), HttpStatus>{
+        let content_encoding = match (self.header_value("Content-Encoding"), &self.body){
+            (Some(content_encoding), Some(_)) => content_encoding.clone(),
+            _ => return Ok(())
+        };
+        let body = compression::decompress(&content_encoding, self.body.take().unwrap_or_default(), max_body_size)?;
+        self.header.retain(|key, _| !key.eq_ignore_ascii_case("Content-Encoding"));
+        if let Some((_, length)) = self.header.iter_mut().find(|(key, _)| key.eq_ignore_ascii_case("Content-Length")){
+            *length = body.len().to_string();
+        }
+        self.body = Some(body);
+        Ok(())
     }
 
     fn parse_url_and_get_query_params(relative_path: &str, base_address: &SocketAddr) -> Result<(String, HashMap<String, String>), Box<dyn Error>>{
@@ -66,21 +165,20 @@ impl Parser{
         Ok((full.path().to_string(), query_map))
     }
 
-    fn parse_body(payload: &Request, buffer: &[u8], parsed_len: usize) -> Option<String> {
+    fn parse_body(payload: &Request, buffer: &[u8], parsed_len: usize) -> Option<Vec<u8>> {
         match payload.method.unwrap() {
-            "POST" | "PUT" | "DELETE" => {
+            "GET" | "HEAD" => None,
+            _ => {
+                // Without a Content-Length anything after the headers belongs
+                // to the next request (chunked bodies are decoded separately)
+                let content_length = payload.headers.iter()
+                    .find(|header| header.name.eq_ignore_ascii_case("Content-Length"))
+                    .and_then(|header| str::from_utf8(header.value).ok())
+                    .and_then(|value| value.trim().parse::<usize>().ok())
+                    .unwrap_or(0);
                 let body = &buffer[parsed_len..];
-                let body = {
-                    let b = str::from_utf8(body);
-                    let b = match b {
-                        Ok(valid) => valid.to_string(),
-                        Err(_) => return None
-                    };
-                    Some(b)
-                };
-                return body;
+                Some(body[..content_length.min(body.len())].to_vec())
             }
-            _ => return None
         }
     }
 
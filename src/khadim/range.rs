@@ -0,0 +1,195 @@
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::parser::Parser;
+use super::response::{Body, Response};
+use super::http_status::HttpStatus;
+
+// Asking for more ranges than this gets the whole body instead
+const MAX_RANGES: usize = 16;
+
+static BOUNDARIES: AtomicU64 = AtomicU64::new(0);
+
+/// How a GET with a `Range` header is answered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ranges{
+    /// No usable `Range` header, or `If-Range` no longer matches.
+    Full,
+    /// End exclusive byte ranges, sorted with overlaps merged.
+    Partial(Vec<Range<u64>>),
+    Unsatisfiable
+}
+
+/// Picks the ranges of a representation of `len` bytes to send, given its
+/// validators for `If-Range`.
+pub fn select(request: &Parser, etag: Option<&str>, last_modified: Option<&str>, len: u64) -> Ranges{
+    if request.method != "GET"{
+        return Ranges::Full;
+    }
+    let header = match request.header_value("Range"){
+        Some(header) => header,
+        None => return Ranges::Full
+    };
+    if let Some(if_range) = request.header_value("If-Range"){
+        if !if_range_matches(if_range.trim(), etag, last_modified){
+            return Ranges::Full;
+        }
+    }
+    parse(header, len)
+}
+
+/// Parses a header such as `bytes=0-99,-500` against a body of `len` bytes.
+/// Headers that can't be parsed are ignored rather than rejected.
+pub fn parse(header: &str, len: u64) -> Ranges{
+    let specs = match header.trim().split_once('='){
+        Some((unit, specs)) if unit.trim().eq_ignore_ascii_case("bytes") => specs,
+        _ => return Ranges::Full
+    };
+
+    let specs : Vec<&str> = specs.split(',').map(str::trim).filter(|spec| !spec.is_empty()).collect();
+    if specs.is_empty() || specs.len() > MAX_RANGES{
+        return Ranges::Full;
+    }
+
+    let mut ranges = Vec::new();
+    for spec in specs{
+        let (first, last) = match spec.split_once('-'){
+            Some(bounds) => bounds,
+            None => return Ranges::Full
+        };
+        let range = if first.is_empty(){
+            // The last `suffix` bytes
+            match number(last){
+                Some(suffix) => len.saturating_sub(suffix)..len,
+                None => return Ranges::Full
+            }
+        }
+        else{
+            let start = match number(first){
+                Some(start) => start,
+                None => return Ranges::Full
+            };
+            let end = match last{
+                "" => len,
+                last => match number(last){
+                    Some(last) if last >= start => last.saturating_add(1).min(len),
+                    _ => return Ranges::Full
+                }
+            };
+            start..end
+        };
+        if range.start < range.end{
+            ranges.push(range);
+        }
+    }
+    if ranges.is_empty(){
+        return Ranges::Unsatisfiable;
+    }
+
+    ranges.sort_by_key(|range| range.start);
+    let mut merged : Vec<Range<u64>> = Vec::new();
+    for range in ranges{
+        match merged.last_mut(){
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range)
+        }
+    }
+    Ranges::Partial(merged)
+}
+
+/// `Content-Range` value for `range` of a body of `len` bytes.
+pub fn content_range(range: &Range<u64>, len: u64) -> String{
+    format!("bytes {}-{}/{}", range.start, range.end - 1, len)
+}
+
+/// Advertises byte ranges on complete GET and HEAD responses held in memory,
+/// and cuts the body down to the ranges the request asks for.
+pub(crate) fn apply(request: &Parser, resp: &mut Response){
+    if (request.method != "GET" && request.method != "HEAD") || !resp.status.starts_with("200"){
+        return;
+    }
+    let len = match resp.body.as_bytes(){
+        Some(body) => body.len() as u64,
+        None => return
+    };
+    // A handler can opt out with `Accept-Ranges: none`
+    match resp.header("Accept-Ranges"){
+        Some(accept) if !accept.eq_ignore_ascii_case("bytes") => return,
+        Some(_) => {}
+        None => resp.headers.push(("Accept-Ranges".to_string(), "bytes".to_string()))
+    }
+
+    let ranges = match select(request, resp.header("ETag"), resp.header("Last-Modified"), len){
+        Ranges::Full => return,
+        Ranges::Unsatisfiable => {
+            resp.status = HttpStatus::RangeNotSatisfiable.to_string();
+            resp.headers = vec![
+                ("Content-Range".to_string(), format!("bytes */{len}")),
+                ("Content-Length".to_string(), "0".to_string())
+            ];
+            resp.body = Body::empty();
+            return
+        }
+        Ranges::Partial(ranges) => ranges
+    };
+
+    let body = match std::mem::take(&mut resp.body){
+        Body::Full(body) => body,
+        Body::Stream(_) => return
+    };
+    resp.status = HttpStatus::PartialContent.to_string();
+    let body = match ranges.as_slice(){
+        [range] => {
+            resp.set_header("Content-Range", content_range(range, len));
+            body[range.start as usize..range.end as usize].to_vec()
+        }
+        ranges => {
+            let boundary = format!("{:016x}{:08x}", nanos(), BOUNDARIES.fetch_add(1, Ordering::Relaxed));
+            let content_type = resp.header("Content-Type").map(str::to_string);
+            resp.set_header("Content-Type", format!("multipart/byteranges; boundary={boundary}"));
+            byteranges(&body, ranges, content_type.as_deref(), &boundary)
+        }
+    };
+    resp.set_header("Content-Length", body.len().to_string());
+    resp.body = Body::Full(body);
+}
+
+fn byteranges(body: &[u8], ranges: &[Range<u64>], content_type: Option<&str>, boundary: &str) -> Vec<u8>{
+    let len = body.len() as u64;
+    let mut parts = Vec::new();
+    for range in ranges{
+        parts.extend_from_slice(format!("\r\n--{boundary}\r\n").as_bytes());
+        if let Some(content_type) = content_type{
+            parts.extend_from_slice(format!("Content-Type: {content_type}\r\n").as_bytes());
+        }
+        parts.extend_from_slice(format!("Content-Range: {}\r\n\r\n", content_range(range, len)).as_bytes());
+        parts.extend_from_slice(&body[range.start as usize..range.end as usize]);
+    }
+    parts.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+    parts
+}
+
+// Only a strong validator proves the client's partial copy is still current
+fn if_range_matches(if_range: &str, etag: Option<&str>, last_modified: Option<&str>) -> bool{
+    if if_range.starts_with('"') || if_range.starts_with("W/"){
+        return !if_range.starts_with("W/") && etag.is_some_and(|etag| !etag.starts_with("W/") && etag == if_range);
+    }
+    match (httpdate::parse_http_date(if_range), last_modified.map(httpdate::parse_http_date)){
+        (Ok(date), Some(Ok(modified))) => date == modified,
+        _ => false
+    }
+}
+
+// Digits only, `str::parse` would also take a sign
+fn number(text: &str) -> Option<u64>{
+    let text = text.trim();
+    if text.is_empty() || !text.bytes().all(|byte| byte.is_ascii_digit()){
+        return None;
+    }
+    text.parse().ok()
+}
+
+fn nanos() -> u64{
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64
+}
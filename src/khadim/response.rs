@@ -1,116 +1,247 @@
-use std::error::Error;
-use std::{collections::HashMap, io::Read, net::SocketAddr};
-use tokio::net::TcpStream;
+use std::any::{Any, TypeId};
+use std::sync::Arc;
+use std::{collections::HashMap, net::SocketAddr};
 use std::fmt;
+use serde::de::DeserializeOwned;
+use futures::Stream;
+use std::pin::Pin;
+use std::time::{Duration, SystemTime};
 
 use super::parser::Parser;
 use super::caller::AsyncReturn;
+use super::conditional::{self, Precondition};
+use super::error::HandlerError;
+use super::http_status::HttpStatus;
 use super::http_header::HttpHeader;
+use super::sse::{self, EventSender, DEFAULT_HEARTBEAT};
 use std::boxed::Box;
 
-pub struct ResponseWriter<'a>{
-    pub conn: &'a TcpStream,
+// Owns everything it needs, so async handlers can keep it across awaits
+pub struct ResponseWriter{
     pub address:  SocketAddr,
-    response_map: HashMap<String, String>
+    status: Option<String>,
+    headers: Vec<(String, String)>,
+    body: Option<Body>
 }
 
-impl<'a> ResponseWriter<'a> {
-    pub fn new(conn: &'a TcpStream, address: SocketAddr) -> Self{
-        let response_map = HashMap::new();
-        ResponseWriter{conn, address, response_map}
+impl ResponseWriter {
+    pub fn new(address: SocketAddr) -> Self{
+        ResponseWriter{address, status: None, headers: Vec::new(), body: None}
     }
 
-    fn set_response(&mut self, key: String, value: String) -> Option<String>{
-        return self.response_map.insert(key, value) 
+    fn set_response(&mut self, key: &str, value: String) -> Option<String>{
+        for (name, existing) in self.headers.iter_mut(){
+            if name.eq_ignore_ascii_case(key){
+                return Some(std::mem::replace(existing, value));
+            }
+        }
+        self.headers.push((key.to_string(), value));
+        None
     }
 
     pub fn set_content_type(&mut self, value: String) -> Option<String>{
-        return self.response_map.insert("Content-Type".to_string(), value);
+        self.set_response("Content-Type", value)
     }
 
     pub fn set_header(&mut self, header: HttpHeader) -> Option<String>{
         let parsed_header = header.as_str();
         let key = parsed_header.0;
         let value = parsed_header.1;
-        return self.response_map.insert(key.to_string(), value.to_string());
+        self.set_response(key, value.to_string())
     }
 
     pub fn set_body(&mut self, body: String){
-        self.set_response("Content-Length".to_string(), format!("{}", body.len()));
-        self.set_response("Body".to_string(), body);
+        self.body = Some(Body::Full(body.into_bytes()));
+    }
+
+    /// Sets a body that is not text, e.g. an image or a compressed payload.
+    /// Remember to set a matching `Content-Type`.
+    pub fn set_body_bytes(&mut self, body: Vec<u8>){
+        self.body = Some(Body::Full(body));
+    }
+
+    /// Sends the body as it is produced instead of buffering it. Chunks are
+    /// written with chunked transfer-encoding and the stream is dropped as
+    /// soon as the client goes away.
+    pub fn set_body_stream<S>(&mut self, stream: S)
+    where
+        S: Stream<Item = Result<Vec<u8>, std::io::Error>> + Send + 'static
+    {
+        self.body = Some(Body::Stream(Box::pin(stream)));
+    }
+
+    /// Turns the response into a `text/event-stream` and returns the sender
+    /// for its events. Spawn a task that keeps sending while the handler
+    /// returns `response()` as usual.
+    pub fn event_stream(&mut self) -> EventSender{
+        self.event_stream_with_heartbeat(DEFAULT_HEARTBEAT)
+    }
+
+    pub fn event_stream_with_heartbeat(&mut self, heartbeat: Duration) -> EventSender{
+        let (sender, stream) = sse::channel(heartbeat);
+        self.set_content_type("text/event-stream".to_string());
+        self.set_header(HttpHeader::CacheControl("no-cache".to_string()));
+        self.set_body_stream(stream);
+        sender
     }
 
-    pub fn set_body_from_html(&mut self, file_path: &str) -> Result<(), Box<dyn Error>>{
-        let mut file = std::fs::File::open(file_path)?;
-        let mut body = String::new();
-        file.read_to_string(&mut body)?;
-        self.set_response("Content-Length".to_string(), format!("{}", body.len()));
-        self.set_response("Body".to_string(), body);
+    pub fn set_body_from_html(&mut self, file_path: &str) -> std::io::Result<()>{
+        let body = std::fs::read(file_path)?;
+        self.body = Some(Body::Full(body));
         self.set_content_type("text/html".to_string());
         Ok(())
     }
 
+    /// Reads the file into the body, with its content type guessed from the
+    /// extension.
+    pub fn set_body_from_file(&mut self, file_path: &str) -> std::io::Result<()>{
+        let body = std::fs::read(file_path)?;
+        self.body = Some(Body::Full(body));
+        self.set_content_type(mime_guess::from_path(file_path).first_or_octet_stream().to_string());
+        Ok(())
+    }
+
     pub fn set_status(&mut self, status_code : impl fmt::Display) {
-        self.response_map.insert("Status".to_string(), status_code.to_string());
+        self.status = Some(status_code.to_string());
     }
 
     pub fn response(&mut self) -> AsyncReturn{
-        if !self.response_map.contains_key("Content-Type"){
-            self.set_response(
-                "Content-Type".to_string(),
-                "text/plain".to_string()
-            );
+        let response = self.finish();
+        Ok(Box::pin(async move {
+            Ok(response)
+        }))
+    }
+
+    // Builds the response right away, for callers that aren't handlers
+    pub(crate) fn finish(&mut self) -> Response{
+        let status = self.status.clone().unwrap_or_else(|| "200 OK".to_string());
+        let body = self.body.take().unwrap_or_default();
+
+        let content_type = self.headers.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Content-Type"))
+            .map(|(_, value)| value.clone())
+            .unwrap_or_else(|| "text/plain".to_string());
+
+        let framing = match &body{
+            Body::Full(bytes) => ("Content-Length".to_string(), bytes.len().to_string()),
+            Body::Stream(_) => ("Transfer-Encoding".to_string(), "chunked".to_string())
+        };
+        let mut headers = vec![
+            ("Content-Type".to_string(), Response::with_charset(&content_type)),
+            framing
+        ];
+        for (name, value) in &self.headers{
+            if name.eq_ignore_ascii_case("Content-Type") || name.eq_ignore_ascii_case("Content-Length") ||
+                name.eq_ignore_ascii_case("Transfer-Encoding"){
+                continue
+            }
+            headers.push((name.clone(), value.clone()));
+        }
+
+        Response{status, headers, body}
+    }
+
+}
+
+pub type BodyStream = Pin<Box<dyn Stream<Item = Result<Vec<u8>, std::io::Error>> + Send>>;
+
+pub enum Body{
+    Full(Vec<u8>),
+    Stream(BodyStream)
+}
+
+impl Body{
+    pub fn empty() -> Self{
+        Body::Full(Vec::new())
+    }
+
+    /// The buffered bytes, `None` for a streamed body.
+    pub fn as_bytes(&self) -> Option<&[u8]>{
+        match self{
+            Body::Full(bytes) => Some(bytes),
+            Body::Stream(_) => None
         }
+    }
+}
+
+impl Default for Body{
+    fn default() -> Self{
+        Body::empty()
+    }
+}
 
-        if !self.response_map.contains_key("Body"){
-            self.set_response(
-                "Body".to_string(),
-                "".to_string()
-            );
-            self.set_response(
-                "Content-Length".to_string(),
-                "0".to_string()
-            );
+impl fmt::Debug for Body{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self{
+            Body::Full(bytes) => f.debug_tuple("Full").field(&bytes.len()).finish(),
+            Body::Stream(_) => f.write_str("Stream")
         }
-        if !self.response_map.contains_key("Status"){
-            self.set_response(
-                "Status".to_string(),
-                "200 OK".to_string()
-            );
+    }
+}
+
+/// A response produced by a callback, written to the socket by the server.
+#[derive(Debug, Default)]
+pub struct Response{
+    pub status: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Body
+}
+
+impl Response{
+    pub fn header(&self, name: &str) -> Option<&str>{
+        self.headers.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Replaces the header if the response has it, otherwise adds it.
+    pub fn set_header(&mut self, name: &str, value: impl Into<String>){
+        let value = value.into();
+        match self.headers.iter_mut().find(|(key, _)| key.eq_ignore_ascii_case(name)){
+            Some((_, existing)) => *existing = value,
+            None => self.headers.push((name.to_string(), value))
         }
+    }
 
-        let mut header_string = String::new();
-        for header in self.response_map.keys(){
-            if header.contains("Status") || header.contains("Content-Type") || 
-                header.contains("Content-Length") || header.contains("Body") { continue }
-            header_string.push_str(&format!("{}: {}\r\n", header, self.response_map.get(header).unwrap()));
+    /// Status line and headers, terminated by the blank line.
+    pub fn head(&self) -> Vec<u8>{
+        let mut head = format!("HTTP/1.1 {} \r\n", self.status);
+        for (name, value) in &self.headers{
+            head.push_str(&format!("{}: {}\r\n", name, value));
         }
-        header_string.push_str("\r\n");
-
-        let payload = format!(
-            "HTTP/1.1 {} \r\n\
-            Content-Type: {}; charset=utf-8\r\n\
-            Content-Length: {}\r\n\
-            {}\
-            {}",
-            self.response_map.get("Status").unwrap(),
-            self.response_map.get("Content-Type").unwrap(),
-            self.response_map.get("Content-Length").unwrap(),
-            header_string,
-            self.response_map.get("Body").unwrap(),
-        );
-
-        println!("{payload}");
+        head.push_str("\r\n");
+        head.into_bytes()
+    }
 
-        Ok(Box::pin(async move {
-            payload
-        }))
+    /// Head and buffered body. A streamed body is left for the server to
+    /// write chunk by chunk.
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let mut payload = self.head();
+        if let Body::Full(body) = &self.body{
+            payload.extend_from_slice(body);
+        }
+        payload
     }
 
+    // Textual types are served as UTF-8 unless the caller picked a charset
+    fn with_charset(content_type: &str) -> String{
+        let lower = content_type.to_ascii_lowercase();
+        let textual = lower.starts_with("text/") || lower.starts_with("application/json") ||
+            lower.starts_with("application/javascript") || lower.starts_with("application/xml");
+        if textual && !lower.contains("charset="){
+            return format!("{}; charset=utf-8", content_type);
+        }
+        content_type.to_string()
+    }
 }
 
+// Application state by type, shared by every request
+pub type StateMap = Arc<HashMap<TypeId, Arc<dyn Any + Send + Sync>>>;
+
 pub struct Request {
     pub request: Parser,
+    pub params: HashMap<String, String>,
+    pub(crate) state: StateMap
 }
 
 pub struct MultiForm {
@@ -120,7 +251,65 @@ pub struct MultiForm {
 
 impl Request{
     pub fn new(request: Parser) -> Self{
-        Request{request}
+        Request{request, params: HashMap::new(), state: StateMap::default()}
+    }
+
+    pub fn with_params(request: Parser, params: HashMap<String, String>) -> Self{
+        Request{request, params, state: StateMap::default()}
+    }
+
+    pub fn with_state(mut self, state: StateMap) -> Self{
+        self.state = state;
+        self
+    }
+
+    /// State registered with `Server::with_state`.
+    pub fn state<T: Send + Sync + 'static>(&self) -> Option<Arc<T>>{
+        let state = self.state.get(&TypeId::of::<T>())?.clone();
+        state.downcast::<T>().ok()
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str>{
+        self.params.get(name).map(|value| value.as_str())
+    }
+
+    /// Checks the conditional headers against the current validators of the
+    /// resource, before a handler changes it. Fails with a 412, or a 304 for
+    /// GET and HEAD.
+    pub fn preconditions(&self, etag: Option<&str>, last_modified: Option<SystemTime>) -> Result<(), HandlerError>{
+        match conditional::evaluate(&self.request, etag, last_modified){
+            Precondition::Proceed => Ok(()),
+            Precondition::NotModified => Err(HandlerError::new(HttpStatus::NotModified, "")),
+            Precondition::Failed => Err(HandlerError::new(HttpStatus::PreconditionFailed, "Precondition Failed"))
+        }
+    }
+
+    /// `Last-Event-ID` sent by a reconnecting event-stream client.
+    pub fn last_event_id(&self) -> Option<&str>{
+        self.request.header_value("Last-Event-ID").map(|id| id.as_str())
+    }
+
+    /// Raw request body, empty when the request had none.
+    pub fn body(&self) -> &[u8]{
+        match &self.request.body{
+            Some(body) => body,
+            None => &[]
+        }
+    }
+
+    /// Body as UTF-8 text, `None` when there is no body or it isn't valid UTF-8.
+    pub fn text(&self) -> Option<&str>{
+        let body = self.request.body.as_ref()?;
+        std::str::from_utf8(body).ok()
+    }
+
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error>{
+        serde_json::from_slice(self.body())
+    }
+
+    /// Decodes an `application/x-www-form-urlencoded` body.
+    pub fn form<T: DeserializeOwned>(&self) -> Result<T, serde_urlencoded::de::Error>{
+        serde_urlencoded::from_bytes(self.body())
     }
 
     pub fn parse_multipart_form(&self) -> Option<HashMap<String, MultiForm>>{
@@ -128,37 +317,32 @@ impl Request{
         use std::io::{Cursor, Read};
 
         let mut form_fields = HashMap::new();
-        let content_type = &self.request.header.get("content-type").unwrap();
+        let content_type = self.request.header_value("content-type")?;
         let boundary_prefix = "boundary=";
         let boundary = content_type
             .split(';')
             .find(|&part| part.trim().starts_with(boundary_prefix))?
             .trim()[boundary_prefix.len()..]
+            .trim_matches('"')
             .to_string();
 
-        let cursor = Cursor::new(self.request.clone().body.unwrap());
+        let cursor = Cursor::new(self.body());
         let mut multipart = Multipart::with_body(cursor, boundary);
 
-        while let Ok(field) = multipart.read_entry() {
-            if field.is_none() {
-                break;
+        while let Ok(Some(mut field)) = multipart.read_entry() {
+            let name = field.headers.name.to_string();
+            // Parts carrying a filename are kept as raw bytes so binary uploads survive
+            if name == "file" || field.headers.filename.is_some() {
+                let mut file_content = Vec::new();
+                field.data.read_to_end(&mut file_content).ok()?;
+                let mf = MultiForm{generic_value: None, file: Some(file_content)};
+                form_fields.insert(name, mf);
             }
-            if field.as_ref().is_some(){
-                let header = field.as_ref().unwrap().headers.name.to_string();
-                if header == "file" {
-                    let mut file_content = Vec::new();
-                    let name = field.as_ref().unwrap().headers.name.to_string();
-                    field.unwrap().data.read_to_end(&mut file_content).unwrap();
-                    let mf = MultiForm{generic_value: None, file: Some(file_content)};
-                    form_fields.insert(name, mf);
-                }
-                else{
-                    let mut field_value = String::new();
-                    let name = field.as_ref().unwrap().headers.name.to_string();
-                    field.unwrap().data.read_to_string(&mut field_value).unwrap();
-                    let mf = MultiForm{generic_value: Some(field_value), file: None};
-                    form_fields.insert(name, mf);
-                 }
+            else{
+                let mut field_value = String::new();
+                field.data.read_to_string(&mut field_value).ok()?;
+                let mf = MultiForm{generic_value: Some(field_value), file: None};
+                form_fields.insert(name, mf);
             }
         }
 
@@ -174,7 +358,7 @@ impl Request{
         };
 
          // Parse the form-urlencoded data into a Vec of tuples
-        let parsed: Vec<(String, String)> = form_urlencoded::parse(body.as_bytes())
+        let parsed: Vec<(String, String)> = form_urlencoded::parse(body)
         .into_owned()
         .collect();
 
@@ -1,111 +1,426 @@
 #![allow(dead_code)]
 use std::collections::HashMap;
-use std::hash::{Hash, Hasher};
-use std::pin::Pin;
-use std::future::Future;
+use std::sync::Arc;
 
-use super::response::{Request, ResponseWriter};
+use percent_encoding::percent_decode_str;
+
+use super::response::{Request, StateMap};
 use super::http_method::HttpMethod;
-use super::caller::{default_404, default_500};
+use super::caller::WsReturn;
+use super::handler::{ErrorHandler, Handler};
+use super::middleware::{Middleware, MiddlewareStack};
+use super::static_files::{ServeDir, FILE_PARAM};
+use super::websocket::WebSocket;
 
-type AsyncReturn = Result<Pin<Box<dyn Future<Output = String> + Send>>, Box<dyn std::error::Error>>;
+pub type Callback = Arc<dyn Handler>;
+pub type ErrorCallback = Arc<dyn ErrorHandler>;
+type WsCallback = fn(Request, WebSocket) -> WsReturn;
 
 #[derive(Debug, Clone)]
 pub struct Router{
-    pub router_elem_mapper: HashMap<Route, RouterElement>,
-    pub not_found_func: Option<fn(Request, ResponseWriter) -> AsyncReturn >,
-    pub internal_server_error: Option<fn(Request, ResponseWriter) -> AsyncReturn >
+    root: RouteNode,
+    pub not_found_func: Option<Callback>,
+    pub method_not_allowed_func: Option<Callback>,
+    pub error_handler: Option<ErrorCallback>,
+    pub state: StateMap,
+    pub middleware: MiddlewareStack
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Router {
+    /// Error handlers left as `None` fall back to the ones of the enclosing
+    /// router, or to the defaults at the top.
     pub fn new() -> Router{
         Router{
-            router_elem_mapper: HashMap::new(),
-            not_found_func: Some(default_404),
-            internal_server_error: Some(default_500)
+            root: RouteNode::default(),
+            not_found_func: None,
+            method_not_allowed_func: None,
+            error_handler: None,
+            state: StateMap::default(),
+            middleware: MiddlewareStack::default()
         }
     }
 
-    pub fn add_route(&mut self, path: &'static str, method: &'static str, callback_function: fn(Request, ResponseWriter) -> AsyncReturn ) -> bool{
-        let path = path.to_string();
+    /// Registers a callback for `path`. Segments starting with `:` capture a
+    /// single segment (`/users/:id`) and a trailing `*name` segment captures
+    /// the rest of the path (`/static/*file`).
+    pub fn add_route(&mut self, path: &str, method: &str, callback_function: impl Handler) -> bool{
+        let method = match HttpMethod::parse(method){
+            Some(method) => method,
+            None => return false
+        };
 
-        let method = match method {
-            "GET" => HttpMethod::GET,
-            "POST" => HttpMethod::POST,
-            "PUT" => HttpMethod::PUT,
-            "DELETE" => HttpMethod::DELETE,
-            _ => panic!("Unexpected HTTP method"),
+        let segments = match Segment::parse_path(path){
+            Some(segments) => segments,
+            None => return false
         };
 
-        let route = Route::new(path, method);
+        let node = match self.root.insert(&segments){
+            Some(node) => node,
+            None => return false
+        };
 
-        if self.router_elem_mapper.contains_key(&route){
+        if node.elements.contains_key(&method){
             return false;
         }
-        
+
         let element = RouterElement{
-            callback_function,
+            callback_function: Arc::new(callback_function),
         };
 
-        self.router_elem_mapper.insert(route, element);
+        node.elements.insert(method, element);
 
         true
     }
 
-    pub fn fetch_func(&self, path: &str, method: &str) -> Option<fn(Request, ResponseWriter) -> AsyncReturn>{
-        let path = path.to_string();
+    /// Adds a middleware around every request the router answers, including
+    /// 404 and 405 responses and WebSocket handshakes. The first one added
+    /// runs first.
+    pub fn add_middleware(&mut self, middleware: impl Middleware){
+        Arc::make_mut(&mut self.middleware).push(Arc::new(middleware));
+    }
+
+    /// Answers paths the router has no route for, including every path
+    /// below its prefix once mounted.
+    pub fn set_not_found_handler(&mut self, callback_function: impl Handler){
+        self.not_found_func = Some(Arc::new(callback_function));
+    }
+
+    pub fn set_method_not_allowed_handler(&mut self, callback_function: impl Handler){
+        self.method_not_allowed_func = Some(Arc::new(callback_function));
+    }
+
+    /// Answers errors returned by the handlers and middleware of the router.
+    pub fn set_error_handler(&mut self, callback_function: impl ErrorHandler){
+        self.error_handler = Some(Arc::new(callback_function));
+    }
 
-        let method = match method {
-            "GET" => HttpMethod::GET,
-            "POST" => HttpMethod::POST,
-            "PUT" => HttpMethod::PUT,
-            "DELETE" => HttpMethod::DELETE,
-            _ => panic!("Unexpected HTTP method"),
+    /// Registers a WebSocket endpoint. Upgrade requests for `path` go to
+    /// `callback_function`, other requests to the regular routes on it.
+    pub fn add_websocket_route(&mut self, path: &str, callback_function: WsCallback) -> bool{
+        let segments = match Segment::parse_path(path){
+            Some(segments) => segments,
+            None => return false
         };
 
-        let route = Route::new(path, method);
+        let node = match self.root.insert(&segments){
+            Some(node) => node,
+            None => return false
+        };
 
-        if self.router_elem_mapper.contains_key(&route){
-            let re = match self.router_elem_mapper.get(&route){
-                Some(r) => r,
-                None => return None
-            };
-            return Some(re.callback_function)
+        if node.websocket.is_some(){
+            return false;
+        }
+
+        node.websocket = Some(callback_function);
+
+        true
+    }
+
+    /// Maps `prefix` onto the directory of `dir` for GET and HEAD requests.
+    pub fn serve_dir(&mut self, prefix: &str, dir: ServeDir) -> bool{
+        let prefix = prefix.trim_end_matches('/');
+        let files = format!("{prefix}/*{FILE_PARAM}");
+        let root = if prefix.is_empty() { "/" } else { prefix };
+        self.add_route(root, "GET", dir.clone()) && self.add_route(&files, "GET", dir)
+    }
+
+    /// Serves every path under `prefix` from `router`, with its middleware
+    /// running after this router's and its error handlers taking precedence.
+    /// Routes registered here are matched first when they are more specific.
+    /// `prefix` may capture parameters, but can't end in a wildcard.
+    pub fn mount(&mut self, prefix: &str, router: Router) -> bool{
+        let segments = match Segment::parse_path(prefix){
+            Some(segments) => segments,
+            None => return false
+        };
+
+        if matches!(segments.last(), Some(Segment::Wildcard(_))){
+            return false;
+        }
+
+        let node = match self.root.insert(&segments){
+            Some(node) => node,
+            None => return false
+        };
+
+        if node.mount.is_some(){
+            return false;
+        }
+
+        node.mount = Some(Box::new(router));
+
+        true
+    }
+
+    pub fn lookup_websocket(&self, path: &str) -> Option<(WsCallback, HashMap<String, String>, Scope)>{
+        let segments : Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        let mut params = Vec::new();
+        let mut scope = Scope::default();
+        let callback = self.find_websocket(&segments, &mut params, &mut scope)?;
+        let params = params.into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect();
+        Some((callback, params, scope))
+    }
+
+    fn find_websocket<'n>(&'n self, segments: &[&str], params: &mut Vec<(&'n str, String)>, scope: &mut Scope) -> Option<WsCallback>{
+        scope.enter(self);
+        match self.root.find(segments, params)?{
+            Found::Node(node) => node.websocket,
+            Found::Mount(router, remaining) => {
+                router.find_websocket(&segments[segments.len() - remaining..], params, scope)
+            }
+        }
+    }
+
+    /// Looks up the callback for `path` and returns it together with the
+    /// captured path parameters.
+    pub fn fetch_func(&self, path: &str, method: &str) -> Option<(Callback, HashMap<String, String>)>{
+        match self.lookup(path, method){
+            RouteLookup::Found(callback, params) => Some((callback, params)),
+            _ => None
+        }
+    }
+
+    /// Like `fetch_func`, but tells an unknown path apart from a known path
+    /// that has no callback for `method`. HEAD falls back to the GET callback
+    /// and OPTIONS is answered from the route table unless registered.
+    pub fn lookup(&self, path: &str, method: &str) -> RouteLookup{
+        self.route(path, method).0
+    }
+
+    /// Like `lookup`, and also returns the middleware and error handlers of
+    /// the mounted routers the path went through.
+    pub fn route(&self, path: &str, method: &str) -> (RouteLookup, Scope){
+        let mut scope = Scope::default();
+        let method = match HttpMethod::parse(method){
+            Some(method) => method,
+            None => {
+                scope.enter(self);
+                return (RouteLookup::NotFound, scope)
+            }
+        };
+
+        let segments : Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        let mut params = Vec::new();
+        let lookup = self.resolve(&segments, &method, &mut params, &mut scope);
+        (lookup, scope)
+    }
+
+    fn resolve<'n>(&'n self, segments: &[&str], method: &HttpMethod, params: &mut Vec<(&'n str, String)>, scope: &mut Scope) -> RouteLookup{
+        scope.enter(self);
+
+        let node = match self.root.find(segments, params){
+            Some(Found::Node(node)) => node,
+            Some(Found::Mount(router, remaining)) => {
+                return router.resolve(&segments[segments.len() - remaining..], method, params, scope)
+            }
+            None => return RouteLookup::NotFound
+        };
+
+        let element = match node.elements.get(method){
+            Some(element) => element,
+            None if *method == HttpMethod::HEAD && node.elements.contains_key(&HttpMethod::GET) => {
+                &node.elements[&HttpMethod::GET]
+            }
+            None if *method == HttpMethod::OPTIONS => {
+                return RouteLookup::Options(node.allowed_methods())
+            }
+            None if node.websocket.is_some() && (*method == HttpMethod::GET || *method == HttpMethod::HEAD) => {
+                return RouteLookup::UpgradeRequired
+            }
+            None => {
+                return RouteLookup::MethodNotAllowed(node.allowed_methods())
+            }
+        };
+
+        let params = params.drain(..)
+            .map(|(name, value)| (name.to_string(), value))
+            .collect();
+
+        RouteLookup::Found(element.callback_function.clone(), params)
+    }
+
+}
+
+/// Middleware and error handlers collected while routing a request, from
+/// the outermost router to the mounted router that answers it.
+#[derive(Debug, Clone, Default)]
+pub struct Scope{
+    pub middleware: Vec<Arc<dyn Middleware>>,
+    pub not_found_func: Option<Callback>,
+    pub method_not_allowed_func: Option<Callback>,
+    pub error_handler: Option<ErrorCallback>
+}
+
+impl Scope{
+    fn enter(&mut self, router: &Router){
+        self.middleware.extend(router.middleware.iter().cloned());
+        if router.not_found_func.is_some(){
+            self.not_found_func = router.not_found_func.clone();
+        }
+        if router.method_not_allowed_func.is_some(){
+            self.method_not_allowed_func = router.method_not_allowed_func.clone();
+        }
+        if router.error_handler.is_some(){
+            self.error_handler = router.error_handler.clone();
         }
-        None
     }
+}
 
+#[derive(Debug)]
+pub enum RouteLookup{
+    Found(Callback, HashMap<String, String>),
+    MethodNotAllowed(Vec<HttpMethod>),
+    Options(Vec<HttpMethod>),
+    // Only a WebSocket endpoint lives at the path
+    UpgradeRequired,
+    NotFound
 }
 
 #[derive(Clone, Debug)]
 pub struct RouterElement {
-    pub callback_function: fn(Request, ResponseWriter) -> AsyncReturn,
+    pub callback_function: Callback,
 }
 
-// Switch hashmap string with the route struct
-#[derive(Debug, Clone)]
-pub struct Route{
-    pub path: String,
-    pub method: HttpMethod
+#[derive(Debug, Clone, PartialEq)]
+enum Segment{
+    Static(String),
+    Param(String),
+    Wildcard(String)
 }
 
-impl Route{
-    fn new(path: String, method: HttpMethod) -> Self{
-        Self { path, method }
+impl Segment{
+    fn parse_path(path: &str) -> Option<Vec<Segment>>{
+        let raw : Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        let mut segments = Vec::new();
+        for (index, part) in raw.iter().enumerate(){
+            let segment = if let Some(name) = part.strip_prefix(':'){
+                if name.is_empty() { return None }
+                Segment::Param(name.to_string())
+            }
+            else if let Some(name) = part.strip_prefix('*'){
+                // A wildcard swallows the remainder, so it has to come last
+                if name.is_empty() || index != raw.len() - 1 { return None }
+                Segment::Wildcard(name.to_string())
+            }
+            else{
+                Segment::Static(part.to_string())
+            };
+            segments.push(segment);
+        }
+        Some(segments)
     }
 }
 
-impl PartialEq for Route {
-    fn eq(&self, other: &Route) -> bool {
-        self.path == other.path && self.method.to_string() == other.method.to_string()
-    }
+// Segment tree, matched in priority order static > param > wildcard
+#[derive(Debug, Clone, Default)]
+struct RouteNode{
+    static_children: HashMap<String, RouteNode>,
+    param_child: Option<(String, Box<RouteNode>)>,
+    wildcard_child: Option<(String, Box<RouteNode>)>,
+    elements: HashMap<HttpMethod, RouterElement>,
+    websocket: Option<WsCallback>,
+    mount: Option<Box<Router>>
+}
+
+enum Found<'n>{
+    Node(&'n RouteNode),
+    // The mounted router and how many trailing segments are left for it
+    Mount(&'n Router, usize)
 }
 
-impl Eq for Route{}
+impl RouteNode{
+    fn allowed_methods(&self) -> Vec<HttpMethod>{
+        let mut allowed : Vec<HttpMethod> = self.elements.keys().cloned().collect();
+        if self.websocket.is_some() && !self.elements.contains_key(&HttpMethod::GET){
+            allowed.push(HttpMethod::GET);
+        }
+        if self.elements.contains_key(&HttpMethod::GET) && !self.elements.contains_key(&HttpMethod::HEAD){
+            allowed.push(HttpMethod::HEAD);
+        }
+        if !self.elements.contains_key(&HttpMethod::OPTIONS){
+            allowed.push(HttpMethod::OPTIONS);
+        }
+        allowed.sort();
+        allowed
+    }
+
+    fn is_endpoint(&self) -> bool{
+        !self.elements.is_empty() || self.websocket.is_some()
+    }
 
-impl Hash for Route {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.path.hash(state);
-        self.method.to_string().hash(state);
+    fn insert(&mut self, segments: &[Segment]) -> Option<&mut RouteNode>{
+        let (first, rest) = match segments.split_first(){
+            Some(split) => split,
+            None => return Some(self)
+        };
+        let child = match first{
+            Segment::Static(name) => {
+                self.static_children.entry(name.clone()).or_default()
+            }
+            Segment::Param(name) => {
+                let (existing, child) = self.param_child
+                    .get_or_insert_with(|| (name.clone(), Box::default()));
+                // `/users/:id` and `/users/:name` can never be told apart
+                if existing != name { return None }
+                child.as_mut()
+            }
+            Segment::Wildcard(name) => {
+                let (existing, child) = self.wildcard_child
+                    .get_or_insert_with(|| (name.clone(), Box::default()));
+                if existing != name { return None }
+                child.as_mut()
+            }
+        };
+        child.insert(rest)
     }
+
+    fn find<'n>(&'n self, segments: &[&str], params: &mut Vec<(&'n str, String)>) -> Option<Found<'n>>{
+        let (first, rest) = match segments.split_first(){
+            Some(split) => split,
+            None => {
+                if self.is_endpoint(){
+                    return Some(Found::Node(self))
+                }
+                return self.mount.as_deref().map(|router| Found::Mount(router, 0))
+            }
+        };
+
+        if let Some(child) = self.static_children.get(*first){
+            if let Some(found) = child.find(rest, params){
+                return Some(found);
+            }
+        }
+
+        if let Some((name, child)) = &self.param_child{
+            let checkpoint = params.len();
+            params.push((name.as_str(), decode_segment(first)));
+            if let Some(found) = child.find(rest, params){
+                return Some(found);
+            }
+            params.truncate(checkpoint);
+        }
+
+        if let Some((name, child)) = &self.wildcard_child{
+            if child.is_endpoint(){
+                let remainder : Vec<String> = segments.iter().map(|s| decode_segment(s)).collect();
+                params.push((name.as_str(), remainder.join("/")));
+                return Some(Found::Node(child));
+            }
+        }
+
+        // Nothing more specific matched, the rest belongs to the mounted router
+        self.mount.as_deref().map(|router| Found::Mount(router, segments.len()))
+    }
+}
+
+fn decode_segment(segment: &str) -> String{
+    percent_decode_str(segment).decode_utf8_lossy().to_string()
 }
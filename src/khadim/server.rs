@@ -1,20 +1,159 @@
 #![allow(dead_code)]
+use std::collections::HashMap;
+use std::io::ErrorKind;
 use std::net::{IpAddr, SocketAddr};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::any::{Any, TypeId};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use futures::{FutureExt, StreamExt};
 use anyhow::{Error, Result};
 
-use super::{parser::Parser, response::{Request,ResponseWriter}, router::Router};
+use super::{parser::Parser, response::{Body, BodyStream, Request, Response, ResponseWriter}, router::{ErrorCallback, Router, RouteLookup, Scope}};
+use super::http_header::HttpHeader;
+use super::http_method::HttpMethod;
+use super::caller::{default_404, default_405, default_426, default_error, default_options, AsyncReturn, WsReturn};
+use super::handler::{ErrorHandler, Handler};
+use super::error::HandlerError;
+use super::middleware::{Middleware, Next};
+use super::websocket::{self, WebSocket, DEFAULT_MAX_MESSAGE_SIZE};
+use super::static_files::ServeDir;
+use super::tls::{CertificateStore, TLS};
+use super::chunked::{ChunkedDecoder, ChunkedError};
+use super::http_status::HttpStatus;
+use super::http2;
+use super::conditional;
+use super::range;
+use super::compression;
+use tokio_rustls::server::TlsStream;
 
-use std::pin::Pin;
-use std::future::Future;
-type AsyncReturn = Result<Pin<Box<dyn Future<Output = String> + Send>>, Box<dyn std::error::Error>>;
+
+// A byte stream a request can be served over, plain or TLS wrapped
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send {
+    fn tcp(&self) -> &TcpStream;
+
+    // Protocol picked during the TLS handshake
+    fn alpn_protocol(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+impl Connection for TcpStream {
+    fn tcp(&self) -> &TcpStream {
+        self
+    }
+}
+
+impl Connection for TlsStream<TcpStream> {
+    fn tcp(&self) -> &TcpStream {
+        self.get_ref().0
+    }
+
+    fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.get_ref().1.alpn_protocol()
+    }
+}
+
+// Largest request body read into memory unless set_max_body_size says otherwise
+const DEFAULT_MAX_BODY_SIZE: usize = 8 * 1024 * 1024;
+// Largest request line and headers, anything longer is answered with 431
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+enum ReadError {
+    // Peer went away before a full request arrived
+    Closed,
+    // Answer with this status and close the connection
+    Rejected(HttpStatus)
+}
+
+// Stops the certificate watcher once the accept loop is gone
+struct WatchGuard(JoinHandle<()>);
+
+impl Drop for WatchGuard {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+// The matched handler and its error handling, innermost in the middleware
+// stack so middleware sees the response that is actually sent
+struct Endpoint {
+    handler: Arc<dyn Handler>,
+    error_handler: ErrorCallback,
+    // What the error handler gets in place of the request, which the handler consumed
+    head: Arc<Parser>,
+    params: HashMap<String, String>
+}
+
+impl Handler for Endpoint {
+    fn call(&self, request: Request, writer: ResponseWriter) -> AsyncReturn {
+        let handler = self.handler.clone();
+        let error_handler = self.error_handler.clone();
+        let head = self.head.clone();
+        let params = self.params.clone();
+        Ok(Box::pin(async move {
+            let address = writer.address;
+            let state = request.state.clone();
+            match catch_panic(|| handler.call(request, writer)).await {
+                Ok(resp) => Ok(resp),
+                Err(error) => {
+                    let request = Request::with_params((*head).clone(), params).with_state(state);
+                    Ok(handle_error(&error_handler, request, address, error).await)
+                }
+            }
+        }))
+    }
+}
+
+fn panic_error(payload: Box<dyn Any + Send>) -> HandlerError {
+    let message = match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => payload.downcast_ref::<String>().cloned().unwrap_or_default()
+    };
+    HandlerError::new(HttpStatus::InternalServerError, format!("handler panicked: {message}"))
+}
+
+// Calls into a middleware or handler, turning a panic in the call or the
+// returned future into an error
+async fn catch_panic(call: impl FnOnce() -> AsyncReturn) -> Result<Response, HandlerError> {
+    let resp = match panic::catch_unwind(AssertUnwindSafe(call)) {
+        Ok(resp) => resp?,
+        Err(payload) => return Err(panic_error(payload))
+    };
+    match AssertUnwindSafe(resp).catch_unwind().await {
+        Ok(result) => result,
+        Err(payload) => Err(panic_error(payload))
+    }
+}
+
+// An error handler that fails itself gets a bare 500
+async fn handle_error(error_handler: &ErrorCallback, request: Request, address: SocketAddr, error: HandlerError) -> Response {
+    if error.status.is_server_error() {
+        println!("Error {error}");
+    }
+    match catch_panic(|| error_handler.call(request, ResponseWriter::new(address), error)).await {
+        Ok(resp) => resp,
+        Err(error) => {
+            println!("Error in error handler {error}");
+            let mut writer = ResponseWriter::new(address);
+            writer.set_status(HttpStatus::InternalServerError);
+            writer.finish()
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Server{
     pub port: u16,
     pub address: String,
-    router: Router
+    router: Router,
+    tls: Option<TLS>,
+    tls_watch_interval: Option<Duration>,
+    max_body_size: usize,
+    max_message_size: usize
 }
 
 impl Server{
@@ -25,22 +164,129 @@ impl Server{
         let address = address.parse::<IpAddr>()?;
         let address = format!("{}:{}", address, port);
         let router = Router::new();
-        let server = Server{port, address, router};
+        let server = Server{port, address, router, tls: None, tls_watch_interval: None, max_body_size: DEFAULT_MAX_BODY_SIZE, max_message_size: DEFAULT_MAX_MESSAGE_SIZE};
         Ok(server)
     }
 
-    pub fn add_route(&mut self, path: &'static str, method: &'static str, callback_function: fn(Request, ResponseWriter) -> AsyncReturn){
+    /// Registers a handler, either an `#[api_callback]` function or a closure
+    /// taking `(Request, ResponseWriter)`.
+    pub fn add_route(&mut self, path: &str, method: &str, callback_function: impl Handler){
         if !self.router.add_route(path, method, callback_function){
            panic!("ERROR adding route ..");
         }
     }
 
-    fn validate_port(port: u16) -> Result<()>{
-        match port{
-            _ => Ok(())
+    /// Registers a WebSocket endpoint. Middleware runs before the handshake
+    /// and can refuse it by answering without calling `next`.
+    pub fn add_websocket_route(&mut self, path: &str, callback_function: fn(Request, WebSocket) -> WsReturn){
+        if !self.router.add_websocket_route(path, callback_function){
+           panic!("ERROR adding websocket route ..");
+        }
+    }
+
+    /// Serves the files below `dir` under `prefix`, e.g.
+    /// `server.serve_dir("/static", ServeDir::new("./assets"))`.
+    pub fn serve_dir(&mut self, prefix: &str, dir: ServeDir){
+        if !self.router.serve_dir(prefix, dir){
+           panic!("ERROR adding static route ..");
+        }
+    }
+
+    /// Serves every path under `prefix` from `router`. Its middleware runs
+    /// after the server's and its error handlers apply below `prefix`.
+    pub fn mount(&mut self, prefix: &str, router: Router){
+        if !self.router.mount(prefix, router){
+           panic!("ERROR mounting router ..");
         }
     }
 
+    /// Adds a middleware that runs around every handler, in the order added.
+    /// For WebSocket routes it runs before the handshake.
+    pub fn add_middleware(&mut self, middleware: impl Middleware){
+        self.router.add_middleware(middleware);
+    }
+
+    pub fn set_not_found_handler(&mut self, callback_function: impl Handler){
+        self.router.set_not_found_handler(callback_function);
+    }
+
+    pub fn set_method_not_allowed_handler(&mut self, callback_function: impl Handler){
+        self.router.set_method_not_allowed_handler(callback_function);
+    }
+
+    /// Answers requests whose handler or middleware returned an error or
+    /// panicked, including extractor rejections. The request it gets has the
+    /// path parameters but not the body, which went to the handler.
+    pub fn set_error_handler(&mut self, callback_function: impl ErrorHandler){
+        self.router.set_error_handler(callback_function);
+    }
+
+    /// Like `set_error_handler`, for server errors only. Other errors keep the
+    /// default response.
+    pub fn set_internal_server_error_handler(&mut self, callback_function: impl Handler){
+        self.set_error_handler(move |request: Request, writer: ResponseWriter, error: HandlerError| {
+            if error.status.is_server_error() {
+                return callback_function.call(request, writer);
+            }
+            default_error(request, writer, error)
+        });
+    }
+
+    /// Serves HTTPS using the PEM certificate chain and private key at the
+    /// given paths, for clients that don't match an SNI certificate.
+    pub fn enable_tls(&mut self, cert_path: &str, key_path: &str) -> Result<(), Error>{
+        match &self.tls{
+            Some(tls) => tls.certificates.set_default_files(cert_path, key_path),
+            None => {
+                self.tls = Some(TLS::new(cert_path, key_path)?);
+                Ok(())
+            }
+        }
+    }
+
+    /// Serves the certificate at the given paths to clients asking for
+    /// `hostname` through SNI. Enables TLS if `enable_tls` was not called, in
+    /// which case clients without a matching name fail the handshake.
+    pub fn add_tls_certificate(&mut self, hostname: &str, cert_path: &str, key_path: &str) -> Result<(), Error>{
+        let tls = self.tls.get_or_insert_with(|| TLS::with_store(CertificateStore::new()));
+        tls.certificates.insert_files(hostname, cert_path, key_path)
+    }
+
+    /// Handle to the live certificate set, for swapping certificates while
+    /// the server is running. Existing connections keep their certificate.
+    pub fn tls_certificates(&self) -> Option<CertificateStore>{
+        self.tls.as_ref().map(|tls| tls.certificates.clone())
+    }
+
+    /// Reloads certificate files from disk whenever they change, checked
+    /// every `interval` once the server is listening.
+    pub fn watch_tls_certificates(&mut self, interval: Duration){
+        self.tls_watch_interval = Some(interval);
+    }
+
+    /// Makes `state` available to handlers through `State<T>` or
+    /// `Request::state`. One value is kept per type.
+    pub fn with_state<T: Send + Sync + 'static>(&mut self, state: T) -> &mut Self{
+        Arc::make_mut(&mut self.router.state).insert(TypeId::of::<T>(), Arc::new(state));
+        self
+    }
+
+    /// Requests with a larger body, before or after undoing its
+    /// `Content-Encoding`, are answered with 413 Payload Too Large.
+    pub fn set_max_body_size(&mut self, max_body_size: usize){
+        self.max_body_size = max_body_size;
+    }
+
+    /// WebSocket messages above this size close the connection with 1009.
+    pub fn set_max_websocket_message_size(&mut self, max_message_size: usize){
+        self.max_message_size = max_message_size;
+    }
+
+    fn validate_port(port: u16) -> Result<()>{
+        let _ = port;
+        Ok(())
+    }
+
     async fn bind(&self) -> Result<TcpListener, Error>{
         TcpListener::bind(self.address.clone()).await.map_err(Error::from)
     }
@@ -58,33 +304,25 @@ impl Server{
     async fn listen(&mut self) -> Result<(), Box<dyn std::error::Error>>{
         let listener = self.bind().await?;
         println!("Listening on {}", listener.local_addr().unwrap());
+        let _watcher = self.tls.as_ref().zip(self.tls_watch_interval).map(|(tls, interval)| {
+            WatchGuard(tls.certificates.watch(interval))
+        });
         loop{
             match listener.accept().await {
-                Ok(mut conn) => {
+                Ok((stream, address)) => {
                     let router = self.router.clone();
+                    let tls = self.tls.clone();
+                    let limits = (self.max_body_size, self.max_message_size);
                     tokio::spawn(async move{
-                        let mut keep_alive = true;
-                        loop {
-                            let parser = Server::read_request(&mut conn).await;
-                            match &parser {
-                                Some(pa) => {
-                                    let headers = &pa.header;
-                                    if let Some(connection_header) = headers.get("Connection") {
-                                        if connection_header == "close" {
-                                            keep_alive = false;
-                                        }
-                                    } 
-                                }
-                                None => {
-                                    break
+                        match tls {
+                            Some(tls) => {
+                                match tls.accept(stream).await {
+                                    Ok(stream) => Server::serve_connection((stream, address), router, limits).await,
+                                    Err(err) => println!("TLS handshake with {} failed {}", address, err)
                                 }
                             }
-                            Server::handle_request(&mut conn, parser.clone(), &router).await;
-                            if !keep_alive {
-                                break
-                            }
+                            None => Server::serve_connection((stream, address), router, limits).await
                         }
-                        conn.0.shutdown().await.unwrap_or_else(|_|{})
                     });
                 }
                 Err(err) => {
@@ -94,78 +332,394 @@ impl Server{
         }
     }
 
-    async fn read_request(stream: &mut (TcpStream, SocketAddr)) -> Option<Parser>{
+    // `limits` are the largest request body and WebSocket message
+    async fn serve_connection<S: Connection + 'static>(mut conn: (S, SocketAddr), router: Router, limits: (usize, usize)){
+        let (max_body_size, max_message_size) = limits;
+        let mut keep_alive = true;
+        // Bytes read past the current request, e.g. the next pipelined request
         let mut buffer : Vec<u8> = Vec::new();
-        let mut temp_buffer = [0; 1024];
-        let mut parsed_req_res : Option<Parser> = None;
+        if conn.0.alpn_protocol() == Some(b"h2") || Server::read_preface(&mut conn, &mut buffer).await {
+            return http2::serve_connection(conn, buffer, router, max_body_size).await;
+        }
         loop {
-            let index = match stream.0.read(&mut temp_buffer).await{
-                Ok(index) => index,
-                Err(_) => {
+            let parser = match Server::read_request(&mut conn, &mut buffer, max_body_size).await {
+                Ok(parser) => parser,
+                Err(ReadError::Closed) => break,
+                Err(ReadError::Rejected(status)) => {
+                    Server::reject(&mut conn, status).await;
                     break
                 }
             };
-            buffer.extend_from_slice(&temp_buffer[..index]);
-            let base_address = &stream.0.local_addr().unwrap();
-            if let Some(parsed_req) = Server::check_parsed_result(&buffer, base_address){
-                if parsed_req_res.is_none(){
-                    parsed_req_res = Some(parsed_req);
+            if websocket::is_upgrade_request(&parser) {
+                if let Some((callback, params, scope)) = router.lookup_websocket(&parser.path) {
+                    let request = Request::with_params(parser, params).with_state(router.state.clone());
+                    match Server::before_upgrade(request, scope, conn.1).await {
+                        Ok(request) => return Server::upgrade(conn, buffer, request, callback, max_message_size).await,
+                        Err(mut resp) => {
+                            resp.set_header("Connection", "close");
+                            let _ = Server::write_response(&mut conn, resp).await;
+                            break
+                        }
+                    }
+                }
+            }
+            if let Some(connection_header) = parser.header_value("Connection") {
+                if connection_header.eq_ignore_ascii_case("close") {
+                    keep_alive = false;
                 }
+            }
+            // Framed by both headers, an intermediary may have split it differently
+            if parser.header_value("Transfer-Encoding").is_some() && parser.header_value("Content-Length").is_some() {
+                keep_alive = false;
+            }
+            if !Server::handle_request(&mut conn, Some(parser), &router).await {
+                break
+            }
+            if !keep_alive {
                 break
             }
         }
-        return parsed_req_res;
+        let _ = conn.0.shutdown().await;
     }
 
-    async fn handle_request(stream: &mut (TcpStream, SocketAddr), parser: Option<Parser>, router: &Router) {
-        let parser = parser.unwrap();
-        let fetched_func = match router.fetch_func(&parser.path, &parser.method){
-            Some(func) => func,
-            None => {
-                router.not_found_func.unwrap()
+    // Cleartext HTTP/2 clients with prior knowledge open with the connection
+    // preface, anything else is HTTP/1
+    async fn read_preface<S: Connection>(stream: &mut (S, SocketAddr), buffer: &mut Vec<u8>) -> bool{
+        let mut temp_buffer = [0; 1024];
+        loop {
+            if buffer.len() >= http2::PREFACE.len() {
+                return buffer.starts_with(http2::PREFACE);
+            }
+            if !http2::PREFACE.starts_with(buffer) {
+                return false;
+            }
+            if Server::read_more(stream, buffer, &mut temp_buffer).await.is_err() {
+                return false;
+            }
+        }
+    }
+
+    // Runs the middleware in scope ahead of a WebSocket handshake. The request
+    // comes back once it has passed the whole stack, otherwise the response
+    // a middleware answered with is sent instead of upgrading.
+    async fn before_upgrade(request: Request, scope: Scope, address: SocketAddr) -> Result<Request, Response>{
+        if scope.middleware.is_empty() {
+            return Ok(request);
+        }
+        let passed = Arc::new(Mutex::new(None));
+        let slot = passed.clone();
+        let handler = move |request: Request, mut writer: ResponseWriter| -> AsyncReturn {
+            *slot.lock().unwrap() = Some(request);
+            writer.response()
+        };
+        let head = request.request.without_body();
+        let params = request.params.clone();
+        let state = request.state.clone();
+        let next = Next::new(Arc::new(scope.middleware), Arc::new(handler));
+        let resp = match catch_panic(|| next.run(request, ResponseWriter::new(address))).await {
+            Ok(resp) => resp,
+            Err(error) => {
+                let error_handler = scope.error_handler.unwrap_or_else(|| Arc::new(default_error));
+                let request = Request::with_params(head, params).with_state(state);
+                handle_error(&error_handler, request, address, error).await
+            }
+        };
+        let request = passed.lock().unwrap().take();
+        request.ok_or(resp)
+    }
+
+    // Completes the handshake and hands the connection to the WebSocket callback
+    async fn upgrade<S: Connection + 'static>(mut conn: (S, SocketAddr), buffer: Vec<u8>, request: Request, callback: fn(Request, WebSocket) -> WsReturn, max_message_size: usize){
+        let accept = match websocket::handshake(&request.request) {
+            Ok(accept) => accept,
+            Err(status) => {
+                let headers = vec![
+                    ("Sec-WebSocket-Version".to_string(), "13".to_string()),
+                    ("Content-Length".to_string(), "0".to_string()),
+                    ("Connection".to_string(), "close".to_string())
+                ];
+                let resp = Response{status: status.to_string(), headers, body: Body::empty()};
+                let _ = Server::write_response(&mut conn, resp).await;
+                let _ = conn.0.shutdown().await;
+                return
+            }
+        };
+        let headers = vec![
+            ("Upgrade".to_string(), "websocket".to_string()),
+            ("Connection".to_string(), "Upgrade".to_string()),
+            ("Sec-WebSocket-Accept".to_string(), accept)
+        ];
+        let resp = Response{status: HttpStatus::SwitchingProtocols.to_string(), headers, body: Body::empty()};
+        if Server::write_response(&mut conn, resp).await.is_err() {
+            return
+        }
+        let socket = WebSocket::new(Box::new(conn.0), conn.1, buffer, max_message_size);
+        callback(request, socket).await;
+    }
+
+    // Reads one request, head and body, leaving any bytes after it in `buffer`
+    async fn read_request<S: Connection>(stream: &mut (S, SocketAddr), buffer: &mut Vec<u8>, max_body_size: usize) -> Result<Parser, ReadError>{
+        let mut temp_buffer = [0; 1024];
+        let base_address = stream.0.tcp().local_addr().unwrap();
+        let (mut parser, parsed_len) = loop {
+            if let Some(parsed_req) = Server::check_parsed_result(buffer, &base_address)?{
+                break parsed_req
+            }
+            if buffer.len() > MAX_HEAD_SIZE {
+                return Err(ReadError::Rejected(HttpStatus::RequestHeaderFieldsTooLarge));
+            }
+            Server::read_more(stream, buffer, &mut temp_buffer).await?;
+        };
+
+        let consumed = if let Some(transfer_encoding) = parser.header_value("Transfer-Encoding") {
+            if !parser.is_chunked() {
+                // The body length can't be known without chunked framing
+                return Err(ReadError::Rejected(HttpStatus::BadRequest));
+            }
+            // Only chunked is decoded, any coding under it would reach the handler as is
+            if transfer_encoding.split(',').filter(|coding| !coding.trim().is_empty()).count() > 1 {
+                return Err(ReadError::Rejected(HttpStatus::NotImplemented));
+            }
+            Server::send_continue(stream, &parser).await?;
+            let mut decoder = ChunkedDecoder::new(max_body_size);
+            let body_len = loop {
+                match decoder.decode(&buffer[parsed_len..]) {
+                    Ok(Some(body_len)) => break body_len,
+                    Ok(None) => Server::read_more(stream, buffer, &mut temp_buffer).await?,
+                    Err(ChunkedError::TooLarge) => return Err(ReadError::Rejected(HttpStatus::PayloadTooLarge)),
+                    Err(ChunkedError::Malformed(reason)) => {
+                        println!("Rejecting request {}", reason);
+                        return Err(ReadError::Rejected(HttpStatus::BadRequest))
+                    }
+                }
+            };
+            let (body, trailers) = decoder.into_parts();
+            parser.body = Some(body);
+            parser.trailers = trailers;
+            parsed_len + body_len
+        }
+        else {
+            let content_length = match parser.content_length() {
+                Ok(content_length) => content_length.unwrap_or(0),
+                Err(err) => {
+                    println!("Rejecting request {}", err);
+                    return Err(ReadError::Rejected(HttpStatus::BadRequest))
+                }
+            };
+            if content_length > max_body_size {
+                return Err(ReadError::Rejected(HttpStatus::PayloadTooLarge));
+            }
+            if content_length > 0 && buffer.len() < parsed_len + content_length {
+                Server::send_continue(stream, &parser).await?;
+                while buffer.len() < parsed_len + content_length {
+                    Server::read_more(stream, buffer, &mut temp_buffer).await?;
+                }
+                if parser.body.is_some() {
+                    let body = &buffer[parsed_len..parsed_len + content_length];
+                    parser.body = Some(body.to_vec());
+                }
+            }
+            parsed_len + content_length
+        };
+
+        buffer.drain(..consumed);
+        parser.decompress_body(max_body_size).map_err(ReadError::Rejected)?;
+        Ok(parser)
+    }
+
+    async fn read_more<S: Connection>(stream: &mut (S, SocketAddr), buffer: &mut Vec<u8>, temp_buffer: &mut [u8]) -> Result<(), ReadError>{
+        match stream.0.read(temp_buffer).await {
+            Ok(0) | Err(_) => Err(ReadError::Closed),
+            Ok(index) => {
+                buffer.extend_from_slice(&temp_buffer[..index]);
+                Ok(())
+            }
+        }
+    }
+
+    // Clients sending `Expect: 100-continue` hold the body back until told to go on
+    async fn send_continue<S: Connection>(stream: &mut (S, SocketAddr), parser: &Parser) -> Result<(), ReadError>{
+        if let Some(expect) = parser.header_value("Expect") {
+            if expect.eq_ignore_ascii_case("100-continue") {
+                let interim = format!("HTTP/1.1 {}\r\n\r\n", HttpStatus::Continue);
+                stream.0.write_all(interim.as_bytes()).await.map_err(|_| ReadError::Closed)?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn reject<S: Connection>(stream: &mut (S, SocketAddr), status: HttpStatus){
+        let mut writer = ResponseWriter::new(stream.1);
+        writer.set_status(status);
+        writer.set_header(HttpHeader::Connection("close".to_string()));
+        let resp = writer.finish();
+        let _ = stream.0.write_all(&resp.to_bytes()).await;
+        let _ = stream.0.flush().await;
+    }
+
+    // Runs the callback for the request, falling back to the error handlers.
+    // Shared by HTTP/1 and HTTP/2, which only differ in how the response is written.
+    pub(crate) async fn route(router: &Router, parser: Parser, address: SocketAddr) -> Response {
+        let mut writer = ResponseWriter::new(address);
+        let (lookup, scope) = router.route(&parser.path, &parser.method);
+        let (fetched_func, params) = match lookup{
+            RouteLookup::Found(func, params) => (func, params),
+            RouteLookup::MethodNotAllowed(allowed) => {
+                writer.set_header(HttpHeader::Allow(Server::allow_header(&allowed)));
+                (scope.method_not_allowed_func.unwrap_or_else(|| Arc::new(default_405)), HashMap::new())
+            }
+            RouteLookup::Options(allowed) => {
+                writer.set_header(HttpHeader::Allow(Server::allow_header(&allowed)));
+                (Arc::new(default_options) as Arc<dyn Handler>, HashMap::new())
             }
+            RouteLookup::UpgradeRequired => {
+                writer.set_header(HttpHeader::Upgrade("websocket".to_string()));
+                writer.set_header(HttpHeader::Connection("Upgrade".to_string()));
+                (Arc::new(default_426) as Arc<dyn Handler>, HashMap::new())
+            }
+            RouteLookup::NotFound => {
+                (scope.not_found_func.unwrap_or_else(|| Arc::new(default_404)), HashMap::new())
+            }
+        };
+        // The body only goes to the handler, everything after it needs the head
+        let head = Arc::new(parser.without_body());
+        let error_handler = scope.error_handler.unwrap_or_else(|| Arc::new(default_error));
+        let endpoint = Endpoint{
+            handler: fetched_func,
+            error_handler: error_handler.clone(),
+            head: head.clone(),
+            params: params.clone()
         };
-        let resp = match fetched_func(
-            Request::new(parser.clone()),
-            ResponseWriter::new(&stream.0, stream.1)
-        ){
-            Ok(result) => result,
-            Err(e) => {
-                println!("Error {e}");
-                Box::pin(std::future::ready("Internal Server Error".to_string())) as Pin<Box<dyn Future<Output = String> + Send>>
+        let next = Next::new(Arc::new(scope.middleware), Arc::new(endpoint));
+        let request = Request::with_params(parser, params.clone()).with_state(router.state.clone());
+        let mut resp = match catch_panic(|| next.run(request, writer)).await {
+            Ok(resp) => resp,
+            Err(error) => {
+                // A middleware failed before it produced a response
+                let request = Request::with_params((*head).clone(), params).with_state(router.state.clone());
+                handle_error(&error_handler, request, address, error).await
             }
         };
-        let mut resp = resp.await;
-        if resp == "Internal Server Error" {
-            resp = router.internal_server_error.unwrap()(
-                Request::new(parser.clone()),
-                ResponseWriter::new(&stream.0, stream.1)
-            ).unwrap().await;
+        conditional::apply(&head, &mut resp);
+        range::apply(&head, &mut resp);
+        compression::apply(&head, &mut resp);
+        resp
+    }
+
+    // Returns false when the connection can't be reused afterwards
+    async fn handle_request<S: Connection>(stream: &mut (S, SocketAddr), parser: Option<Parser>, router: &Router) -> bool {
+        let parser = parser.unwrap();
+        let (head_only, version) = (parser.method == "HEAD", parser.version);
+        let mut resp = Server::route(router, parser, stream.1).await;
+        if head_only {
+            // Keep the headers (Content-Length included) but never send a body
+            resp.body = Body::empty();
+            return Server::write_response(stream, resp).await.is_ok();
         }
-        stream.0.write_all(resp.as_bytes()).await.unwrap();
-        stream.0.flush().await.unwrap();
+        if version == 0 && matches!(resp.body, Body::Stream(_)) {
+            // HTTP/1.0 has no chunked coding, the body ends when the connection does
+            resp.headers.retain(|(name, _)| !name.eq_ignore_ascii_case("Transfer-Encoding"));
+            resp.headers.push(("Connection".to_string(), "close".to_string()));
+            if let Body::Stream(body) = std::mem::take(&mut resp.body) {
+                let _ = Server::write_stream(stream, resp.head(), body, false).await;
+            }
+            return false;
+        }
+        Server::write_response(stream, resp).await.is_ok()
     }
 
-    fn check_parsed_result(buffer: &[u8], base_address: &SocketAddr) -> Option<Parser>{
+    async fn write_response<S: Connection>(stream: &mut (S, SocketAddr), mut resp: Response) -> std::io::Result<()> {
+        match std::mem::take(&mut resp.body) {
+            Body::Full(body) => {
+                stream.0.write_all(&resp.head()).await?;
+                stream.0.write_all(&body).await?;
+                stream.0.flush().await
+            }
+            Body::Stream(body) => Server::write_stream(stream, resp.head(), body, true).await
+        }
+    }
+
+    // Writes each chunk as soon as the body stream yields it. Waiting on the
+    // socket between chunks is the backpressure; a closed socket stops the
+    // stream from being polled again.
+    async fn write_stream<S: Connection>(stream: &mut (S, SocketAddr), head: Vec<u8>, mut body: BodyStream, chunked: bool) -> std::io::Result<()> {
+        stream.0.write_all(&head).await?;
+        stream.0.flush().await?;
+        let mut watch_disconnect = true;
+        loop {
+            let next = if watch_disconnect {
+                let mut probe = [0; 1];
+                tokio::select! {
+                    next = body.next() => next,
+                    peeked = stream.0.tcp().peek(&mut probe) => {
+                        match peeked {
+                            Ok(0) | Err(_) => {
+                                return Err(std::io::Error::new(ErrorKind::ConnectionAborted, "client went away"))
+                            }
+                            // Data from the client (a pipelined request or TLS record), stop probing
+                            Ok(_) => {
+                                watch_disconnect = false;
+                                continue
+                            }
+                        }
+                    }
+                }
+            }
+            else {
+                body.next().await
+            };
+            let chunk = match next {
+                Some(Ok(chunk)) => chunk,
+                Some(Err(err)) => {
+                    // The response can't be completed, closing is the only way to tell the client
+                    println!("Error while streaming response {err}");
+                    return Err(err)
+                }
+                None => break
+            };
+            if chunk.is_empty() {
+                continue
+            }
+            if chunked {
+                stream.0.write_all(format!("{:x}\r\n", chunk.len()).as_bytes()).await?;
+                stream.0.write_all(&chunk).await?;
+                stream.0.write_all(b"\r\n").await?;
+            }
+            else {
+                stream.0.write_all(&chunk).await?;
+            }
+            stream.0.flush().await?;
+        }
+        if chunked {
+            stream.0.write_all(b"0\r\n\r\n").await?;
+        }
+        stream.0.flush().await
+    }
+
+    fn allow_header(allowed: &[HttpMethod]) -> String{
+        let allowed : Vec<String> = allowed.iter().map(|method| method.to_string()).collect();
+        allowed.join(", ")
+    }
+
+    // `None` until the whole head has arrived
+    fn check_parsed_result(buffer: &[u8], base_address: &SocketAddr) -> Result<Option<(Parser, usize)>, ReadError>{
         let mut headers = [httparse::EMPTY_HEADER; 64];
         let mut req = httparse::Request::new(&mut headers);
-        let parsed_result = {
-            req.parse(&buffer)
-        };
-        match parsed_result{
+        match req.parse(buffer){
             Ok(httparse::Status::Complete(parsed_len)) => {
-                let parsed_req = Parser::new(req, base_address, buffer, parsed_len);
-                match parsed_req {
-                    Ok(req) => return Some(req),
-                    Err(_) => return None
+                match Parser::new(req, base_address, buffer, parsed_len) {
+                    Ok(parser) => Ok(Some((parser, parsed_len))),
+                    Err(err) => {
+                        println!("Rejecting request {}", err);
+                        Err(ReadError::Rejected(HttpStatus::BadRequest))
+                    }
                 }
             }
-            Ok(httparse::Status::Partial) => {
-                None
-            }
-            Err(e) => {
-                eprintln!("Failed to parse request: {}", e);
-                return None
+            Ok(httparse::Status::Partial) => Ok(None),
+            Err(httparse::Error::TooManyHeaders) => Err(ReadError::Rejected(HttpStatus::RequestHeaderFieldsTooLarge)),
+            Err(err) => {
+                println!("Rejecting request {}", err);
+                Err(ReadError::Rejected(HttpStatus::BadRequest))
             }
         }
     }
@@ -0,0 +1,120 @@
+use std::fmt;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{interval_at, Instant, Interval};
+use futures::Stream;
+
+// Events a handler can queue before `send` waits for the client to catch up
+const CHANNEL_CAPACITY: usize = 16;
+
+pub const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(15);
+
+/// One `text/event-stream` message.
+#[derive(Debug, Clone, Default)]
+pub struct Event{
+    pub id: Option<String>,
+    pub event: Option<String>,
+    pub data: String,
+    pub retry: Option<Duration>
+}
+
+impl Event{
+    pub fn new(data: impl Into<String>) -> Self{
+        Event{data: data.into(), ..Event::default()}
+    }
+
+    pub fn event(mut self, event: impl Into<String>) -> Self{
+        self.event = Some(event.into());
+        self
+    }
+
+    pub fn id(mut self, id: impl Into<String>) -> Self{
+        self.id = Some(id.into());
+        self
+    }
+
+    /// Tells the browser how long to wait before reconnecting.
+    pub fn retry(mut self, retry: Duration) -> Self{
+        self.retry = Some(retry);
+        self
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let mut frame = String::new();
+        // Line breaks would end the field early, so they are dropped from single line fields
+        let single_line = |value: &str| value.replace(['\r', '\n'], "");
+        if let Some(event) = &self.event{
+            frame.push_str(&format!("event: {}\n", single_line(event)));
+        }
+        if let Some(id) = &self.id{
+            frame.push_str(&format!("id: {}\n", single_line(id)));
+        }
+        if let Some(retry) = self.retry{
+            frame.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        for line in self.data.split('\n'){
+            frame.push_str(&format!("data: {}\n", line.strip_suffix('\r').unwrap_or(line)));
+        }
+        frame.push('\n');
+        frame.into_bytes()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Disconnected;
+
+impl fmt::Display for Disconnected{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Event stream client disconnected")
+    }
+}
+
+impl std::error::Error for Disconnected{}
+
+/// Handle for pushing events to one client. Sending fails with
+/// `Disconnected` once the client has gone away, which is the signal to stop
+/// producing events.
+#[derive(Debug, Clone)]
+pub struct EventSender{
+    sender: mpsc::Sender<Vec<u8>>
+}
+
+impl EventSender{
+    pub async fn send(&self, event: Event) -> Result<(), Disconnected>{
+        self.sender.send(event.to_bytes()).await.map_err(|_| Disconnected)
+    }
+
+    /// Sends a `:` comment line, ignored by browsers.
+    pub async fn comment(&self, text: &str) -> Result<(), Disconnected>{
+        let frame = format!(": {}\n\n", text.replace(['\r', '\n'], " "));
+        self.sender.send(frame.into_bytes()).await.map_err(|_| Disconnected)
+    }
+
+    pub fn is_closed(&self) -> bool{
+        self.sender.is_closed()
+    }
+
+    /// Resolves once the client has disconnected.
+    pub async fn closed(&self){
+        self.sender.closed().await
+    }
+}
+
+/// Creates the sender and the body stream for an event-stream response. The
+/// stream ends once every sender is dropped and sends a heartbeat comment
+/// after `heartbeat` without events so proxies keep the connection open.
+pub fn channel(heartbeat: Duration) -> (EventSender, impl Stream<Item = Result<Vec<u8>, std::io::Error>> + Send + 'static){
+    let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+    let ticker = interval_at(Instant::now() + heartbeat, heartbeat);
+    let stream = futures::stream::unfold((receiver, ticker), |(mut receiver, mut ticker): (mpsc::Receiver<Vec<u8>>, Interval)| async move {
+        let frame = tokio::select! {
+            frame = receiver.recv() => {
+                ticker.reset();
+                frame?
+            }
+            _ = ticker.tick() => b": keep-alive\n\n".to_vec()
+        };
+        Some((Ok(frame), (receiver, ticker)))
+    });
+    (EventSender{sender}, stream)
+}
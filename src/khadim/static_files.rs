@@ -0,0 +1,194 @@
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+
+use super::caller::AsyncReturn;
+use super::conditional::{self, Precondition};
+use super::range::{self, Ranges};
+use super::error::HandlerError;
+use super::handler::Handler;
+use super::http_header::HttpHeader;
+use super::http_status::HttpStatus;
+use super::response::{Request, Response, ResponseWriter};
+
+/// Name of the wildcard segment registered by `serve_dir`.
+pub(crate) const FILE_PARAM: &str = "file";
+
+// Escaped in listing links, a trailing `/` marks directories
+const LINK: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'#').add(b'%').add(b'<').add(b'>').add(b'?').add(b'`');
+
+/// Serves the files below a directory. Registered with `Server::serve_dir`,
+/// which maps a URL prefix onto it.
+#[derive(Debug, Clone)]
+pub struct ServeDir{
+    root: PathBuf,
+    listing: bool
+}
+
+impl ServeDir{
+    pub fn new(root: impl Into<PathBuf>) -> Self{
+        ServeDir{root: root.into(), listing: false}
+    }
+
+    /// Lists the entries of directories that have no `index.html`.
+    pub fn listing(mut self, listing: bool) -> Self{
+        self.listing = listing;
+        self
+    }
+
+    async fn serve(&self, request: Request, mut writer: ResponseWriter) -> Result<Response, HandlerError>{
+        let url_path = request.request.path.clone();
+        // Would turn into separators once decoded
+        let raw = url_path.to_ascii_lowercase();
+        if raw.contains("%2f") || raw.contains("%5c"){
+            return Err(HandlerError::new(HttpStatus::BadRequest, "Encoded separators aren't allowed"));
+        }
+        let relative = sanitize(request.param(FILE_PARAM).unwrap_or(""))
+            .ok_or_else(|| HandlerError::new(HttpStatus::BadRequest, "Invalid path"))?;
+
+        let root = tokio::fs::canonicalize(&self.root).await?;
+        let path = resolve(&root, &root.join(&relative)).await.ok_or_else(not_found)?;
+        if !tokio::fs::metadata(&path).await?.is_dir(){
+            return serve_file(&request, &path, writer).await;
+        }
+
+        if !url_path.ends_with('/'){
+            // Relative links in the directory's page need the trailing slash
+            writer.set_status(HttpStatus::MovedPermanently);
+            writer.set_header(HttpHeader::Location(format!("{url_path}/")));
+            return writer.response()?.await;
+        }
+        if let Some(index) = resolve(&root, &path.join("index.html")).await{
+            if tokio::fs::metadata(&index).await?.is_file(){
+                return serve_file(&request, &index, writer).await;
+            }
+        }
+        if !self.listing{
+            return Err(not_found());
+        }
+        list(&path, &url_path, relative.as_os_str().is_empty(), writer).await
+    }
+}
+
+impl Handler for ServeDir{
+    fn call(&self, request: Request, writer: ResponseWriter) -> AsyncReturn{
+        let dir = self.clone();
+        Ok(Box::pin(async move {
+            dir.serve(request, writer).await
+        }))
+    }
+}
+
+fn not_found() -> HandlerError{
+    HandlerError::new(HttpStatus::NotFound, "Not Found")
+}
+
+// Only plain names are kept, so the path can't climb out of the root
+fn sanitize(relative: &str) -> Option<PathBuf>{
+    let mut path = PathBuf::new();
+    for part in relative.split('/').filter(|part| !part.is_empty()){
+        if part.contains('\\') || part.contains('\0'){
+            return None;
+        }
+        let mut components = Path::new(part).components();
+        match (components.next(), components.next()){
+            (Some(Component::Normal(_)), None) => path.push(part),
+            _ => return None
+        }
+    }
+    Some(path)
+}
+
+// Follows symlinks and drops targets that end up outside the root
+async fn resolve(root: &Path, path: &Path) -> Option<PathBuf>{
+    let path = tokio::fs::canonicalize(path).await.ok()?;
+    path.starts_with(root).then_some(path)
+}
+
+async fn serve_file(request: &Request, path: &Path, mut writer: ResponseWriter) -> Result<Response, HandlerError>{
+    let metadata = tokio::fs::metadata(path).await?;
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    writer.set_content_type(mime.to_string());
+    let last_modified = metadata.modified().ok();
+    let etag = last_modified.map(|modified| conditional::etag_for_file(modified, metadata.len()));
+    let date = last_modified.map(conditional::format_date);
+    if let (Some(etag), Some(date)) = (&etag, &date){
+        writer.set_header(HttpHeader::ETag(etag.clone()));
+        writer.set_header(HttpHeader::LastModified(date.clone()));
+    }
+    // The server turns this into a 304 or 412 from the same headers, so the
+    // file doesn't have to be read for it
+    if conditional::evaluate(&request.request, etag.as_deref(), last_modified) != Precondition::Proceed{
+        return writer.response()?.await;
+    }
+
+    // A single range is read on its own, the server cuts anything else out
+    // of the whole file
+    let len = metadata.len();
+    if let Ranges::Partial(ranges) = range::select(&request.request, etag.as_deref(), date.as_deref(), len){
+        if let [part] = ranges.as_slice(){
+            writer.set_status(HttpStatus::PartialContent);
+            writer.set_header(HttpHeader::ContentRange(range::content_range(part, len)));
+            writer.set_header(HttpHeader::AcceptRanges("bytes".to_string()));
+            writer.set_body_bytes(read_range(path, part).await?);
+            return writer.response()?.await;
+        }
+    }
+    writer.set_body_bytes(tokio::fs::read(path).await?);
+    writer.response()?.await
+}
+
+async fn read_range(path: &Path, part: &Range<u64>) -> std::io::Result<Vec<u8>>{
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(part.start)).await?;
+    let mut body = Vec::with_capacity((part.end - part.start) as usize);
+    file.take(part.end - part.start).read_to_end(&mut body).await?;
+    Ok(body)
+}
+
+async fn list(dir: &Path, url_path: &str, is_root: bool, mut writer: ResponseWriter) -> Result<Response, HandlerError>{
+    let mut names = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await?{
+        let mut name = entry.file_name().to_string_lossy().to_string();
+        if entry.file_type().await?.is_dir(){
+            name.push('/');
+        }
+        names.push(name);
+    }
+    names.sort();
+
+    let title = escape_html(&percent_decode_str(url_path).decode_utf8_lossy());
+    let mut html = format!("<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {title}</title></head>\n<body>\n<h1>Index of {title}</h1>\n<ul>\n");
+    if !is_root{
+        html.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for name in names{
+        let link = utf8_percent_encode(&name, LINK).to_string();
+        html.push_str(&format!("<li><a href=\"{}\">{}</a></li>\n", escape_html(&link), escape_html(&name)));
+    }
+    html.push_str("</ul>\n</body>\n</html>\n");
+
+    writer.set_content_type("text/html".to_string());
+    writer.set_body(html);
+    writer.response()?.await
+}
+
+fn escape_html(text: &str) -> String{
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars(){
+        match c{
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c)
+        }
+    }
+    escaped
+}
@@ -1 +1,2 @@
-pub mod test_server;
\ No newline at end of file
+pub mod test_server;
+pub mod test_chunked;
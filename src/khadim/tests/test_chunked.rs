@@ -0,0 +1,59 @@
+#[cfg(test)]
+mod tests {
+    use crate::khadim::chunked::{ChunkedDecoder, ChunkedError};
+
+    #[test]
+    fn test_decode_in_one_piece(){
+        let mut decoder = ChunkedDecoder::new(1024);
+        let buffer = b"5\r\nHello\r\n7;ext=1\r\n, World\r\n0\r\n\r\nGET / HTTP/1.1";
+        let consumed = decoder.decode(buffer).unwrap().unwrap();
+        assert_eq!(&buffer[consumed..], b"GET / HTTP/1.1");
+        let (body, trailers) = decoder.into_parts();
+        assert_eq!(body, b"Hello, World");
+        assert!(trailers.is_empty());
+    }
+
+    #[test]
+    fn test_decode_byte_by_byte(){
+        let mut decoder = ChunkedDecoder::new(1024);
+        let buffer = b"a\r\n0123456789\r\n3\r\nabc\r\n0\r\nX-Checksum: 42\r\n\r\n";
+        for end in 1..buffer.len(){
+            assert_eq!(decoder.decode(&buffer[..end]).unwrap(), None);
+        }
+        assert_eq!(decoder.decode(buffer).unwrap(), Some(buffer.len()));
+        let (body, trailers) = decoder.into_parts();
+        assert_eq!(body, b"0123456789abc");
+        assert_eq!(trailers.get("X-Checksum").unwrap(), "42");
+    }
+
+    #[test]
+    fn test_forbidden_trailers_are_dropped(){
+        let mut decoder = ChunkedDecoder::new(1024);
+        let buffer = b"0\r\nContent-Length: 10\r\nX-Ok: yes\r\n\r\n";
+        decoder.decode(buffer).unwrap().unwrap();
+        let (_, trailers) = decoder.into_parts();
+        assert_eq!(trailers.len(), 1);
+        assert_eq!(trailers.get("X-Ok").unwrap(), "yes");
+    }
+
+    #[test]
+    fn test_size_limit(){
+        let mut decoder = ChunkedDecoder::new(8);
+        assert_eq!(decoder.decode(b"5\r\nHello\r\n5\r\n"), Err(ChunkedError::TooLarge));
+        let mut decoder = ChunkedDecoder::new(8);
+        assert_eq!(decoder.decode(b"ffffffffffffffffff\r\n"), Err(ChunkedError::Malformed("invalid chunk size")));
+    }
+
+    #[test]
+    fn test_malformed_bodies(){
+        let mut decoder = ChunkedDecoder::new(1024);
+        assert!(matches!(decoder.decode(b"zz\r\n"), Err(ChunkedError::Malformed(_))));
+        let mut decoder = ChunkedDecoder::new(1024);
+        assert!(matches!(decoder.decode(b"2\r\nabc\r\n"), Err(ChunkedError::Malformed(_))));
+        let mut decoder = ChunkedDecoder::new(1024);
+        assert!(matches!(decoder.decode(b"0\r\nnot a trailer\r\n\r\n"), Err(ChunkedError::Malformed(_))));
+        let mut decoder = ChunkedDecoder::new(1024);
+        let long_line = vec![b'1'; 5000];
+        assert!(matches!(decoder.decode(&long_line), Err(ChunkedError::Malformed(_))));
+    }
+}
@@ -13,7 +13,19 @@ mod tests {
     use crate::khadim::response::Request;
     use crate::khadim::http_status::HttpStatus;
     use crate::khadim::server::Server;
+    use crate::khadim::router::Router;
+    use crate::khadim::tls::TLS;
     use crate::khadim::http_header::HttpHeader;
+    use crate::khadim::websocket::{Message, WebSocket};
+    use crate::khadim::handler::Handler;
+    use crate::khadim::caller::AsyncReturn;
+    use crate::khadim::error::HandlerError;
+    use crate::khadim::static_files::ServeDir;
+    use crate::khadim::conditional::etag_for_bytes;
+    use crate::khadim::compression::Encoding;
+    use crate::khadim::middleware::Next;
+    use crate::khadim::extract::{Form, HeaderMap, Json, Path, Query, State};
+    use crate::ws_callback;
 
     const START : u8 = 0;
     const EXIT : u8 = 1;
@@ -88,9 +100,9 @@ mod tests {
 
     #[api_callback]
     pub fn process_redirect(_request: Request, mut writer: ResponseWriter){
-        let port = match request.request.body{
+        let port = match request.text(){
             Some(data) => {
-                data
+                data.to_string()
             },
             None => {
                 String::new()
@@ -103,9 +115,9 @@ mod tests {
 
     #[api_callback]
     pub fn process_payload(_request: Request, mut writer: ResponseWriter){
-        let payload = match request.request.body{
+        let payload = match request.text(){
             Some(data) => {
-                data
+                data.to_string()
             },
             None => {
                 String::new()
@@ -130,9 +142,9 @@ mod tests {
 
     #[api_callback]
     pub fn serve_put(_request: Request, mut writer: ResponseWriter){
-        let payload = match request.request.body{
+        let payload = match request.text(){
             Some(data) => {
-                data
+                data.to_string()
             },
             None => {
                 String::new()
@@ -150,10 +162,9 @@ mod tests {
     }
 
     #[api_callback]
-    pub fn serve_slow(_request: Request, mut writer: ResponseWriter){
+    pub async fn serve_slow(_request: Request, mut writer: ResponseWriter){
         writer.set_status(HttpStatus::Ok);
-        use std::thread;
-        thread::sleep(Duration::from_secs(1));
+        tokio::time::sleep(Duration::from_secs(1)).await;
         writer.response()
     }
 
@@ -184,6 +195,40 @@ mod tests {
         writer.response()
     }
 
+    #[api_callback]
+    pub fn serve_user(request: Request, mut writer: ResponseWriter){
+        writer.set_status(HttpStatus::Ok);
+        writer.set_body(format!("user {}", request.param("id").unwrap()));
+        writer.response()
+    }
+
+    #[api_callback]
+    pub fn serve_current_user(_request: Request, mut writer: ResponseWriter){
+        writer.set_status(HttpStatus::Ok);
+        writer.set_body("current user".to_string());
+        writer.response()
+    }
+
+    #[api_callback]
+    pub fn serve_user_post(request: Request, mut writer: ResponseWriter){
+        writer.set_status(HttpStatus::Ok);
+        writer.set_body(format!("{}/{}", request.param("id").unwrap(), request.param("post_id").unwrap()));
+        writer.response()
+    }
+
+    #[api_callback]
+    pub fn serve_wildcard(request: Request, mut writer: ResponseWriter){
+        writer.set_status(HttpStatus::Ok);
+        writer.set_body(request.param("rest").unwrap().to_string());
+        writer.response()
+    }
+
+    #[api_callback]
+    pub fn serve_teapot(_request: Request, mut writer: ResponseWriter){
+        writer.set_status(HttpStatus::ImATeapot);
+        writer.response()
+    }
+
     async fn fetch_port() -> u16{
         let address = "127.0.0.1:0";
         let listener = TcpListener::bind(&address).await.expect("Failed to bind to address");
@@ -452,15 +497,38 @@ mod tests {
         let port = fetch_port().await;
         let mut server = init_server(port);
         server.add_route("/", "POST", serve_get);
+        server.add_route("/", "DELETE", serve_delete);
         let _ = tokio::spawn(async move {
             server.serve().await.unwrap();
         });
         tokio::task::yield_now().await;
-        let status_code = reqwest::get(format!("http://localhost:{port}/"))
+        let response = reqwest::get(format!("http://localhost:{port}/"))
         .await
-        .unwrap()
-        .status();
-        assert_eq!(status_code.as_str(), "404")
+        .unwrap();
+        assert_eq!(response.status().as_str(), "405");
+        let allow = response.headers().get("Allow").unwrap();
+        assert_eq!(allow.to_str().unwrap(), "POST, DELETE, OPTIONS")
+    }
+
+    #[tokio::test]
+    async fn test_custom_method_not_allowed_handler(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_route("/users/:id", "GET", serve_user);
+        server.set_method_not_allowed_handler(serve_teapot);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let client = reqwest::Client::new();
+        let response = client
+        .post(format!("http://localhost:{port}/users/1"))
+        .send()
+        .await
+        .unwrap();
+        assert_eq!(response.status().as_str(), "418");
+        let allow = response.headers().get("Allow").unwrap();
+        assert_eq!(allow.to_str().unwrap(), "GET, HEAD, OPTIONS")
     }
 
     #[tokio::test]
@@ -691,6 +759,1936 @@ mod tests {
 
     }
 
+    #[tokio::test]
+    async fn test_path_params(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_route("/users/:id", "GET", serve_user);
+        server.add_route("/users/:id/posts/:post_id", "GET", serve_user_post);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let body = reqwest::get(format!("http://localhost:{port}/users/42"))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+        assert_eq!(body, "user 42");
+        let body = reqwest::get(format!("http://localhost:{port}/users/a%20b/posts/7"))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+        assert_eq!(body, "a b/7");
+        let status_code = reqwest::get(format!("http://localhost:{port}/users/42/posts"))
+        .await
+        .unwrap()
+        .status();
+        assert_eq!(status_code.as_str(), "404")
+    }
+
+    #[tokio::test]
+    async fn test_static_route_priority(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_route("/users/:id", "GET", serve_user);
+        server.add_route("/users/me", "GET", serve_current_user);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let body = reqwest::get(format!("http://localhost:{port}/users/me"))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+        assert_eq!(body, "current user");
+        let body = reqwest::get(format!("http://localhost:{port}/users/you"))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+        assert_eq!(body, "user you")
+    }
+
+    #[tokio::test]
+    async fn test_wildcard_route(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_route("/static/*rest", "GET", serve_wildcard);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let body = reqwest::get(format!("http://localhost:{port}/static/css/site.css"))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+        assert_eq!(body, "css/site.css");
+        let status_code = reqwest::get(format!("http://localhost:{port}/static"))
+        .await
+        .unwrap()
+        .status();
+        assert_eq!(status_code.as_str(), "404")
+    }
+
+    #[test]
+    fn test_conflicting_param_names(){
+        let mut router = Router::new();
+        assert!(router.add_route("/users/:id", "GET", serve_user));
+        assert!(!router.add_route("/users/:name/posts", "GET", serve_user));
+        assert!(!router.add_route("/files/*rest/more", "GET", serve_wildcard));
+        assert!(!router.add_route("/users/:id", "GET", serve_user));
+    }
+
+    #[tokio::test]
+    async fn test_patch_and_custom_methods(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_route("/", "PATCH", serve_post);
+        server.add_route("/", "PURGE", serve_delete);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let client = reqwest::Client::new();
+        let status_code = client
+        .patch(format!("http://localhost:{port}/"))
+        .send()
+        .await
+        .unwrap()
+        .status();
+        assert_eq!(status_code.as_str(), "201");
+        let status_code = client
+        .request(reqwest::Method::from_bytes(b"PURGE").unwrap(), format!("http://localhost:{port}/"))
+        .send()
+        .await
+        .unwrap()
+        .status();
+        assert_eq!(status_code.as_str(), "204");
+        let status_code = client
+        .request(reqwest::Method::from_bytes(b"PROPFIND").unwrap(), format!("http://localhost:{port}/"))
+        .send()
+        .await
+        .unwrap()
+        .status();
+        assert_eq!(status_code.as_str(), "405");
+        let status_code = client
+        .patch(format!("http://localhost:{port}/missing"))
+        .send()
+        .await
+        .unwrap()
+        .status();
+        assert_eq!(status_code.as_str(), "404")
+    }
+
+    #[tokio::test]
+    async fn test_head_from_get_route(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_route("/", "GET", serve_json_payload);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let client = reqwest::Client::new();
+        let response = client
+        .head(format!("http://localhost:{port}/"))
+        .send()
+        .await
+        .unwrap();
+        assert_eq!(response.status().as_str(), "200");
+        let content_length = response.headers().get("Content-Length").unwrap();
+        assert_eq!(content_length.to_str().unwrap(), "30");
+        assert_eq!(response.text().await.unwrap(), "");
+        // The connection must still be usable after a bodiless response
+        let body = client
+        .get(format!("http://localhost:{port}/"))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+        assert_eq!(body, r#"{"field1":"Hello","field2":42}"#)
+    }
+
+    #[tokio::test]
+    async fn test_automatic_options(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_route("/", "GET", serve_get);
+        server.add_route("/", "POST", serve_post);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let client = reqwest::Client::new();
+        let response = client
+        .request(reqwest::Method::OPTIONS, format!("http://localhost:{port}/"))
+        .send()
+        .await
+        .unwrap();
+        assert_eq!(response.status().as_str(), "204");
+        let allow = response.headers().get("Allow").unwrap();
+        assert_eq!(allow.to_str().unwrap(), "GET, HEAD, POST, OPTIONS")
+    }
+
+    fn write_self_signed_cert(name: &str, hostname: &str) -> (String, String, Vec<u8>){
+        let generated = rcgen::generate_simple_self_signed(vec![hostname.to_string()]).unwrap();
+        let dir = std::env::temp_dir();
+        let cert_path = dir.join(format!("nashar_gah_{}_cert.pem", name));
+        let key_path = dir.join(format!("nashar_gah_{}_key.pem", name));
+        std::fs::write(&cert_path, generated.cert.pem()).unwrap();
+        std::fs::write(&key_path, generated.key_pair.serialize_pem()).unwrap();
+        (
+            cert_path.to_str().unwrap().to_string(),
+            key_path.to_str().unwrap().to_string(),
+            generated.cert.pem().into_bytes()
+        )
+    }
+
+    #[tokio::test]
+    async fn test_tls(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        let (cert_path, key_path, cert_pem) = write_self_signed_cert("test_tls", "localhost");
+        server.enable_tls(&cert_path, &key_path).unwrap();
+        server.add_route("/", "GET", serve_json_payload);
+        server.add_route("/", "PUT", serve_put);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let client = reqwest::Client::builder()
+        .add_root_certificate(reqwest::Certificate::from_pem(&cert_pem).unwrap())
+        .build()
+        .unwrap();
+        let body = client
+        .get(format!("https://localhost:{port}/"))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+        assert_eq!(body, r#"{"field1":"Hello","field2":42}"#);
+        // Second request goes over the same kept-alive TLS connection
+        let status_code = client
+        .put(format!("https://localhost:{port}/"))
+        .body("{id: 1}")
+        .send()
+        .await
+        .unwrap()
+        .status();
+        assert_eq!(status_code.as_str(), "204");
+        let plain = reqwest::get(format!("http://localhost:{port}/")).await;
+        assert!(plain.is_err())
+    }
+
+    #[test]
+    fn test_tls_rejects_bad_pem(){
+        let mut server = init_server(0);
+        assert!(server.enable_tls("./path_that_doesnt_exist.pem", "./path_that_doesnt_exist.pem").is_err());
+        assert!(server.tls_certificates().is_none());
+        assert!(TLS::from_pem(b"not a certificate", b"not a key").is_err());
+    }
+
+    fn sni_client(port: u16, trusted: &[&Vec<u8>]) -> reqwest::Client{
+        let address = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+        let mut builder = reqwest::Client::builder()
+        .resolve("alpha.test", address)
+        .resolve("beta.test", address);
+        for cert in trusted{
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(cert).unwrap());
+        }
+        builder.build().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_tls_sni_certificates(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        let (alpha_cert, alpha_key, alpha_pem) = write_self_signed_cert("sni_alpha", "alpha.test");
+        let (beta_cert, beta_key, beta_pem) = write_self_signed_cert("sni_beta", "beta.test");
+        server.add_tls_certificate("alpha.test", &alpha_cert, &alpha_key).unwrap();
+        server.add_tls_certificate("beta.test", &beta_cert, &beta_key).unwrap();
+        // Setting the fallback certificate afterwards keeps the SNI ones
+        let (default_cert, default_key, _) = write_self_signed_cert("sni_default", "localhost");
+        server.enable_tls(&default_cert, &default_key).unwrap();
+        server.add_route("/", "GET", serve_get);
+        let certificates = server.tls_certificates().unwrap();
+        assert_eq!(certificates.hostnames(), vec!["alpha.test", "beta.test"]);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+
+        let client = sni_client(port, &[&alpha_pem]);
+        let status_code = client.get(format!("https://alpha.test:{port}/")).send().await.unwrap().status();
+        assert_eq!(status_code.as_str(), "200");
+        assert!(client.get(format!("https://beta.test:{port}/")).send().await.is_err());
+
+        let client = sni_client(port, &[&beta_pem]);
+        let status_code = client.get(format!("https://beta.test:{port}/")).send().await.unwrap().status();
+        assert_eq!(status_code.as_str(), "200");
+
+        // Swap beta for a fresh certificate while the server keeps running
+        let rotated = rcgen::generate_simple_self_signed(vec!["beta.test".to_string()]).unwrap();
+        certificates.insert_pem("beta.test", rotated.cert.pem().as_bytes(), rotated.key_pair.serialize_pem().as_bytes()).unwrap();
+        let rotated_pem = rotated.cert.pem().into_bytes();
+        let client = sni_client(port, &[&rotated_pem]);
+        let status_code = client.get(format!("https://beta.test:{port}/")).send().await.unwrap().status();
+        assert_eq!(status_code.as_str(), "200");
+        let client = sni_client(port, &[&beta_pem]);
+        assert!(client.get(format!("https://beta.test:{port}/")).send().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tls_reload_from_disk(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        let (cert_path, key_path, old_pem) = write_self_signed_cert("reload", "localhost");
+        // Works before TLS is enabled as well
+        server.watch_tls_certificates(Duration::from_millis(50));
+        server.enable_tls(&cert_path, &key_path).unwrap();
+        server.add_route("/", "GET", serve_get);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+
+        let old_client = reqwest::Client::builder()
+        .add_root_certificate(reqwest::Certificate::from_pem(&old_pem).unwrap())
+        .build()
+        .unwrap();
+        let status_code = old_client.get(format!("https://localhost:{port}/")).send().await.unwrap().status();
+        assert_eq!(status_code.as_str(), "200");
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let (_, _, new_pem) = write_self_signed_cert("reload", "localhost");
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        // The kept-alive connection still works with the old certificate
+        let status_code = old_client.get(format!("https://localhost:{port}/")).send().await.unwrap().status();
+        assert_eq!(status_code.as_str(), "200");
+
+        let new_client = reqwest::Client::builder()
+        .add_root_certificate(reqwest::Certificate::from_pem(&new_pem).unwrap())
+        .build()
+        .unwrap();
+        let status_code = new_client.get(format!("https://localhost:{port}/")).send().await.unwrap().status();
+        assert_eq!(status_code.as_str(), "200")
+    }
+
+    async fn send_raw(port: u16, request: &[u8]) -> String{
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        stream.write_all(request).await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        String::from_utf8(response).unwrap()
+    }
+
+    #[api_callback]
+    pub fn echo_body(request: Request, mut writer: ResponseWriter){
+        let trailer = request.request.trailers.get("X-Trailer").cloned().unwrap_or_default();
+        writer.set_status(HttpStatus::Ok);
+        writer.set_body(format!("{}|{}", request.text().unwrap_or_default(), trailer));
+        writer.response()
+    }
+
+    #[tokio::test]
+    async fn test_chunked_request_body(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_route("/", "POST", echo_body);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let response = send_raw(port, b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n5\r\nHello\r\n6\r\n World\r\n0\r\nX-Trailer: done\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("Hello World|done"));
+    }
+
+    #[tokio::test]
+    async fn test_chunked_request_body_split_across_reads(){
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_route("/", "POST", echo_body);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        stream.write_all(b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\nExpect: 100-continue\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut interim = [0; 25];
+        stream.read_exact(&mut interim).await.unwrap();
+        assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+        let chunk = "x".repeat(3000);
+        stream.write_all(format!("bb8\r\n{}", chunk).as_bytes()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        stream.write_all(b"\r\n0\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with(&format!("{}|", chunk)));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_chunked_request_body(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_route("/", "POST", echo_body);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let response = send_raw(port, b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\nnope\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+        let response = send_raw(port, b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: gzip\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+        let response = send_raw(port, b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n7fffffff\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large"));
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_transfer_coding(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_route("/", "POST", echo_body);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        for transfer_encoding in ["gzip, chunked", "deflate,chunked", "chunked, chunked"]{
+            let request = format!("POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: {transfer_encoding}\r\n\r\n3\r\nabc\r\n0\r\n\r\n");
+            let response = send_raw(port, request.as_bytes()).await;
+            assert!(response.starts_with("HTTP/1.1 501 Not Implemented"), "{transfer_encoding}");
+        }
+        let response = send_raw(port, b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: Chunked\r\nConnection: close\r\n\r\n3\r\nabc\r\n0\r\n\r\n").await;
+        assert!(response.ends_with("abc|"));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_request_head(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_route("/", "POST", echo_body);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let response = send_raw(port, b"GARBAGE\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+        let headers : String = (0..65).map(|i| format!("X-{i}: {i}\r\n")).collect();
+        let response = send_raw(port, format!("POST / HTTP/1.1\r\n{headers}\r\n").as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large"));
+        // Rejected as soon as it passes the limit, without waiting for the blank line
+        let mut head = b"POST / HTTP/1.1\r\nX-Big: ".to_vec();
+        head.resize(64 * 1024 + 1, b'a');
+        let response = send_raw(port, &head).await;
+        assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large"));
+        let response = send_raw(port, b"POST / HTTP/1.1\r\nHost: localhost\r\nX-Name: caf\xe9\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok").await;
+        assert!(response.ends_with("ok|"));
+    }
+
+    #[tokio::test]
+    async fn test_large_body_read_fully(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_route("/", "POST", echo_body);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let payload = "y".repeat(200_000);
+        let body = reqwest::Client::new()
+        .post(format!("http://localhost:{port}/"))
+        .body(payload.clone())
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+        assert_eq!(body, format!("{}|", payload));
+    }
+
+    #[tokio::test]
+    async fn test_body_too_large(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.set_max_body_size(16);
+        server.add_route("/", "POST", echo_body);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let response = send_raw(port, b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 17\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large"));
+        let response = send_raw(port, b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n9\r\n123456789\r\n9\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large"));
+        let response = send_raw(port, b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 1, 2\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+        let response = send_raw(port, b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\ncontent-length: 10\r\n\r\nabcdefghij").await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+        let response = send_raw(port, b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\nContent-Length: 3\r\nConnection: close\r\n\r\nabc").await;
+        assert!(response.ends_with("abc|"));
+        // The connection is closed after a request framed by both headers
+        let response = send_raw(port, b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n3\r\nabc\r\n0\r\n\r\nPOST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\n\r\ndef").await;
+        assert!(response.ends_with("abc|"));
+        let response = send_raw(port, b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 16\r\nConnection: close\r\n\r\n0123456789abcdef").await;
+        assert!(response.ends_with("0123456789abcdef|"));
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_requests(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_route("/", "POST", echo_body);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let response = send_raw(port, b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nfirstPOST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n6\r\nsecond\r\n0\r\n\r\nPOST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nConnection: close\r\n\r\nthird").await;
+        let bodies : Vec<&str> = response.split("HTTP/1.1 200 OK").skip(1)
+            .map(|part| part.rsplit("\r\n").next().unwrap())
+            .collect();
+        assert_eq!(bodies, vec!["first|", "second|", "third|"]);
+    }
+
+    #[api_callback]
+    pub fn describe_binary_body(request: Request, mut writer: ResponseWriter){
+        let body = request.body();
+        let sum : u64 = body.iter().map(|b| *b as u64).sum();
+        writer.set_status(HttpStatus::Ok);
+        writer.set_body(format!("{} {} {}", body.len(), sum, request.text().is_some()));
+        writer.response()
+    }
+
+    #[api_callback]
+    pub fn describe_binary_upload(request: Request, mut writer: ResponseWriter){
+        let form = request.parse_multipart_form().unwrap();
+        let file = form.get("image").unwrap().file.as_ref().unwrap();
+        let sum : u64 = file.iter().map(|b| *b as u64).sum();
+        writer.set_status(HttpStatus::Ok);
+        writer.set_body(format!("{} {} {}", file.len(), sum, form.get("caption").unwrap().generic_value.as_ref().unwrap()));
+        writer.response()
+    }
+
+    #[api_callback]
+    pub fn read_json_body(request: Request, mut writer: ResponseWriter){
+        use serde::Deserialize;
+        #[derive(Deserialize)]
+        struct Order {
+            item: String,
+            quantity: u32,
+        }
+        let order : Order = request.json()
+            .map_err(|err| HandlerError::new(HttpStatus::BadRequest, format!("Invalid JSON body: {err}")))?;
+        writer.set_status(HttpStatus::Ok);
+        writer.set_body(format!("{} x{}", order.item, order.quantity));
+        writer.response()
+    }
+
+    fn binary_payload() -> Vec<u8>{
+        (0..4096u32).map(|i| (i % 256) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn test_binary_request_body(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_route("/raw", "POST", describe_binary_body);
+        server.add_route("/upload", "POST", describe_binary_upload);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let payload = binary_payload();
+        let sum : u64 = payload.iter().map(|b| *b as u64).sum();
+        let client = reqwest::Client::new();
+        let body = client
+        .post(format!("http://localhost:{port}/raw"))
+        .body(payload.clone())
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+        assert_eq!(body, format!("4096 {} false", sum));
+
+        let form = reqwest::multipart::Form::new()
+            .text("caption", "gradient")
+            .part("image", reqwest::multipart::Part::bytes(payload).file_name("image.bin"));
+        let body = client
+        .post(format!("http://localhost:{port}/upload"))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+        assert_eq!(body, format!("4096 {} gradient", sum));
+    }
+
+    #[tokio::test]
+    async fn test_json_request_body(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_route("/", "POST", read_json_body);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let client = reqwest::Client::new();
+        let body = client
+        .post(format!("http://localhost:{port}/"))
+        .body(r#"{"item": "tea", "quantity": 3}"#)
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+        assert_eq!(body, "tea x3");
+        let status_code = client
+        .post(format!("http://localhost:{port}/"))
+        .body("not json")
+        .send()
+        .await
+        .unwrap()
+        .status();
+        assert_eq!(status_code.as_str(), "400");
+    }
+
+    #[api_callback]
+    pub fn read_form_body(request: Request, mut writer: ResponseWriter){
+        let fields : HashMap<String, String> = request.form()
+            .map_err(|err| HandlerError::new(HttpStatus::BadRequest, format!("Invalid form body: {err}")))?;
+        writer.set_status(HttpStatus::Ok);
+        writer.set_body(format!("{} x{}", fields["item"], fields["quantity"]));
+        writer.response()
+    }
+
+    #[tokio::test]
+    async fn test_form_request_body(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_route("/", "POST", read_form_body);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let client = reqwest::Client::new();
+        let body = client
+        .post(format!("http://localhost:{port}/"))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("item=green+tea&quantity=3%21")
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+        assert_eq!(body, "green tea x3!");
+    }
+
+    #[api_callback]
+    pub fn serve_binary(_request: Request, mut writer: ResponseWriter){
+        writer.set_status(HttpStatus::Ok);
+        writer.set_header(HttpHeader::ContentType("application/octet-stream".to_string()));
+        writer.set_body_bytes((0..4096u32).map(|i| (i % 256) as u8).collect());
+        writer.response()
+    }
+
+    #[tokio::test]
+    async fn test_binary_response_body(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_route("/", "GET", serve_binary);
+        server.add_route("/json", "GET", serve_json_payload);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let response = reqwest::get(format!("http://localhost:{port}/")).await.unwrap();
+        let content_type = response.headers().get("Content-Type").unwrap().to_str().unwrap().to_string();
+        assert_eq!(content_type, "application/octet-stream");
+        assert_eq!(response.bytes().await.unwrap().to_vec(), binary_payload());
+        let response = reqwest::get(format!("http://localhost:{port}/json")).await.unwrap();
+        let content_type = response.headers().get("Content-Type").unwrap().to_str().unwrap();
+        assert_eq!(content_type, "application/json; charset=utf-8");
+    }
+
+    static STREAM_DROPPED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+    struct DropFlag;
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            STREAM_DROPPED.store(true, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    #[api_callback]
+    pub fn serve_stream(_request: Request, mut writer: ResponseWriter){
+        let chunks : Vec<Result<Vec<u8>, std::io::Error>> = vec![
+            Ok(b"first ".to_vec()),
+            Ok(Vec::new()),
+            Ok(b"second ".to_vec()),
+            Ok(b"third".to_vec()),
+        ];
+        writer.set_status(HttpStatus::Ok);
+        writer.set_body_stream(futures::stream::iter(chunks));
+        writer.response()
+    }
+
+    #[api_callback]
+    pub fn serve_endless_stream(_request: Request, mut writer: ResponseWriter){
+        let ticks = futures::stream::unfold(DropFlag, |flag| async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            Some((Ok(b"tick\n".to_vec()), flag))
+        });
+        writer.set_status(HttpStatus::Ok);
+        writer.set_body_stream(ticks);
+        writer.response()
+    }
+
+    #[tokio::test]
+    async fn test_streamed_response(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_route("/", "GET", serve_stream);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let client = reqwest::Client::new();
+        let response = client.get(format!("http://localhost:{port}/")).send().await.unwrap();
+        assert_eq!(response.headers().get("Transfer-Encoding").unwrap(), "chunked");
+        assert!(response.headers().get("Content-Length").is_none());
+        assert_eq!(response.text().await.unwrap(), "first second third");
+        // The connection stays usable after the terminating chunk
+        let body = client.get(format!("http://localhost:{port}/")).send().await.unwrap().text().await.unwrap();
+        assert_eq!(body, "first second third");
+
+        let raw = send_raw(port, b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+        assert!(raw.ends_with("\r\n\r\n6\r\nfirst \r\n7\r\nsecond \r\n5\r\nthird\r\n0\r\n\r\n"));
+        let raw = send_raw(port, b"GET / HTTP/1.0\r\nHost: localhost\r\n\r\n").await;
+        assert!(!raw.contains("Transfer-Encoding"));
+        assert!(raw.ends_with("\r\n\r\nfirst second third"));
+        let raw = send_raw(port, b"HEAD / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+        assert!(raw.contains("Transfer-Encoding: chunked\r\n") && raw.ends_with("\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_streamed_response_client_disconnect(){
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_route("/", "GET", serve_endless_stream);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let mut received = [0; 256];
+        let read = stream.read(&mut received).await.unwrap();
+        assert!(read > 0);
+        assert!(!STREAM_DROPPED.load(std::sync::atomic::Ordering::SeqCst));
+        drop(stream);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(STREAM_DROPPED.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    static SSE_DISCONNECTED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+    #[api_callback]
+    pub fn serve_events(request: Request, mut writer: ResponseWriter){
+        use crate::khadim::sse::Event;
+        let start: u32 = request.last_event_id().and_then(|id| id.parse().ok()).unwrap_or(0);
+        let events = writer.event_stream();
+        tokio::spawn(async move {
+            for id in start + 1..=start + 3{
+                let event = Event::new(format!("line one\nline {id}")).event("update").id(id.to_string());
+                if events.send(event).await.is_err(){
+                    return;
+                }
+            }
+        });
+        writer.response()
+    }
+
+    #[api_callback]
+    pub fn serve_endless_events(_request: Request, mut writer: ResponseWriter){
+        use crate::khadim::sse::Event;
+        let events = writer.event_stream_with_heartbeat(Duration::from_millis(20));
+        tokio::spawn(async move {
+            events.send(Event::new("hello")).await.unwrap();
+            events.closed().await;
+            SSE_DISCONNECTED.store(true, std::sync::atomic::Ordering::SeqCst);
+        });
+        writer.response()
+    }
+
+    #[tokio::test]
+    async fn test_server_sent_events(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_route("/events", "GET", serve_events);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let client = reqwest::Client::new();
+        let response = client.get(format!("http://localhost:{port}/events")).send().await.unwrap();
+        assert_eq!(response.headers().get("Content-Type").unwrap(), "text/event-stream; charset=utf-8");
+        assert_eq!(response.headers().get("Cache-Control").unwrap(), "no-cache");
+        let body = response.text().await.unwrap();
+        assert!(body.starts_with("event: update\nid: 1\ndata: line one\ndata: line 1\n\n"));
+        assert!(body.ends_with("id: 3\ndata: line one\ndata: line 3\n\n"));
+
+        let body = client.get(format!("http://localhost:{port}/events"))
+            .header("Last-Event-ID", "7")
+            .send().await.unwrap().text().await.unwrap();
+        assert!(body.starts_with("event: update\nid: 8\n"));
+        assert!(body.contains("id: 10\n"));
+    }
+
+    #[tokio::test]
+    async fn test_server_sent_events_heartbeat_and_disconnect(){
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_route("/events", "GET", serve_endless_events);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        stream.write_all(b"GET /events HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let mut received = Vec::new();
+        let mut buffer = [0; 256];
+        while !String::from_utf8_lossy(&received).contains(": keep-alive"){
+            let read = stream.read(&mut buffer).await.unwrap();
+            assert!(read > 0);
+            received.extend_from_slice(&buffer[..read]);
+        }
+        assert!(String::from_utf8_lossy(&received).contains("data: hello\n\n"));
+        assert!(!SSE_DISCONNECTED.load(std::sync::atomic::Ordering::SeqCst));
+        drop(stream);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(SSE_DISCONNECTED.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[ws_callback]
+    pub async fn serve_echo_socket(request: Request, mut socket: WebSocket){
+        let room = request.param("room").unwrap_or_default().to_string();
+        socket.set_max_message_size(64);
+        while let Some(Ok(message)) = socket.recv().await{
+            let reply = match message{
+                Message::Text(text) => Message::Text(format!("{room}:{text}")),
+                Message::Binary(data) => Message::Binary(data),
+                _ => continue
+            };
+            if socket.send(reply).await.is_err(){
+                break
+            }
+        }
+    }
+
+    // Client frames have to be masked
+    fn ws_frame(first_byte: u8, payload: &[u8]) -> Vec<u8>{
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![first_byte];
+        if payload.len() < 126{
+            frame.push(0x80 | payload.len() as u8);
+        }
+        else{
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(index, byte)| byte ^ mask[index % 4]));
+        frame
+    }
+
+    async fn read_ws_frame(stream: &mut tokio::net::TcpStream) -> (u8, Vec<u8>){
+        use tokio::io::AsyncReadExt;
+        let mut head = [0; 2];
+        stream.read_exact(&mut head).await.unwrap();
+        assert_eq!(head[1] & 0x80, 0);
+        let mut payload = vec![0; (head[1] & 0x7F) as usize];
+        stream.read_exact(&mut payload).await.unwrap();
+        (head[0], payload)
+    }
+
+    async fn ws_connect(port: u16, path: &str) -> (tokio::net::TcpStream, String){
+        ws_connect_with(port, path, "").await
+    }
+
+    async fn ws_connect_with(port: u16, path: &str, headers: &str) -> (tokio::net::TcpStream, String){
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n{headers}\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut head = Vec::new();
+        let mut byte = [0; 1];
+        while !head.ends_with(b"\r\n\r\n"){
+            stream.read_exact(&mut byte).await.unwrap();
+            head.push(byte[0]);
+        }
+        (stream, String::from_utf8(head).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_websocket_echo(){
+        use tokio::io::AsyncWriteExt;
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_websocket_route("/ws/:room", serve_echo_socket);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+
+        let (mut stream, head) = ws_connect(port, "/ws/lobby").await;
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols"));
+        // Accept value from the RFC 6455 example handshake
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        stream.write_all(&ws_frame(0x81, b"hello")).await.unwrap();
+        assert_eq!(read_ws_frame(&mut stream).await, (0x81, b"lobby:hello".to_vec()));
+
+        // Fragmented text with a ping in between
+        stream.write_all(&ws_frame(0x01, b"frag")).await.unwrap();
+        stream.write_all(&ws_frame(0x89, b"are you there")).await.unwrap();
+        stream.write_all(&ws_frame(0x80, b"mented")).await.unwrap();
+        assert_eq!(read_ws_frame(&mut stream).await, (0x8A, b"are you there".to_vec()));
+        assert_eq!(read_ws_frame(&mut stream).await, (0x81, b"lobby:fragmented".to_vec()));
+
+        stream.write_all(&ws_frame(0x82, &[0, 159, 255])).await.unwrap();
+        assert_eq!(read_ws_frame(&mut stream).await, (0x82, vec![0, 159, 255]));
+
+        stream.write_all(&ws_frame(0x88, &[0x03, 0xE8, b'b', b'y', b'e'])).await.unwrap();
+        assert_eq!(read_ws_frame(&mut stream).await, (0x88, vec![0x03, 0xE8, b'b', b'y', b'e']));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_protocol_errors(){
+        use tokio::io::AsyncWriteExt;
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_websocket_route("/ws/:room", serve_echo_socket);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+
+        // Over the 64 byte message limit
+        let (mut stream, _) = ws_connect(port, "/ws/lobby").await;
+        stream.write_all(&ws_frame(0x82, &[7; 200])).await.unwrap();
+        assert_eq!(read_ws_frame(&mut stream).await, (0x88, 1009u16.to_be_bytes().to_vec()));
+
+        // The limit covers the whole message, not each fragment
+        let (mut stream, _) = ws_connect(port, "/ws/lobby").await;
+        stream.write_all(&ws_frame(0x02, &[7; 40])).await.unwrap();
+        stream.write_all(&ws_frame(0x80, &[7; 40])).await.unwrap();
+        assert_eq!(read_ws_frame(&mut stream).await, (0x88, 1009u16.to_be_bytes().to_vec()));
+
+        let (mut stream, _) = ws_connect(port, "/ws/lobby").await;
+        stream.write_all(&[0x81, 0x02, b'h', b'i']).await.unwrap();
+        assert_eq!(read_ws_frame(&mut stream).await, (0x88, 1002u16.to_be_bytes().to_vec()));
+
+        let (mut stream, _) = ws_connect(port, "/ws/lobby").await;
+        stream.write_all(&ws_frame(0x81, &[0xC3, 0x28])).await.unwrap();
+        assert_eq!(read_ws_frame(&mut stream).await, (0x88, 1007u16.to_be_bytes().to_vec()));
+
+        let (mut stream, _) = ws_connect(port, "/ws/lobby").await;
+        stream.write_all(&ws_frame(0x80, b"orphan")).await.unwrap();
+        assert_eq!(read_ws_frame(&mut stream).await, (0x88, 1002u16.to_be_bytes().to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_handshake_rejections(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_websocket_route("/ws/:room", serve_echo_socket);
+        server.add_route("/chat", "GET", serve_get);
+        server.add_websocket_route("/chat", serve_echo_socket);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+
+        let raw = send_raw(port, b"GET /ws/lobby HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+        assert!(raw.starts_with("HTTP/1.1 426 Upgrade Required"));
+        assert!(raw.contains("Upgrade: websocket\r\n"));
+
+        let raw = send_raw(port, b"GET /ws/lobby HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 8\r\n\r\n").await;
+        assert!(raw.starts_with("HTTP/1.1 426 Upgrade Required"));
+        assert!(raw.contains("Sec-WebSocket-Version: 13\r\n"));
+
+        let raw = send_raw(port, b"GET /ws/lobby HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Key: short\r\nSec-WebSocket-Version: 13\r\n\r\n").await;
+        assert!(raw.starts_with("HTTP/1.1 400 Bad Request"));
+
+        let raw = send_raw(port, b"POST /ws/lobby HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await;
+        assert!(raw.starts_with("HTTP/1.1 405 Method Not Allowed"));
+        assert!(raw.contains("Allow: GET, OPTIONS\r\n"));
+
+        // A WebSocket and a regular route can share a path
+        let raw = send_raw(port, b"GET /chat HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+        assert!(raw.starts_with("HTTP/1.1 200 OK"));
+        let (_, head) = ws_connect(port, "/chat").await;
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols"));
+    }
+
+    #[tokio::test]
+    async fn test_http2_prior_knowledge(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_route("/", "GET", serve_json_payload);
+        server.add_route("/users/:id", "GET", serve_user);
+        server.add_route("/echo", "POST", echo_body);
+        server.add_route("/stream", "GET", serve_stream);
+        server.set_max_body_size(16);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let client = reqwest::Client::builder().http2_prior_knowledge().build().unwrap();
+
+        let response = client.get(format!("http://localhost:{port}/")).send().await.unwrap();
+        assert_eq!(response.version(), http::Version::HTTP_2);
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers().get("Content-Length").unwrap(), "30");
+        assert_eq!(response.text().await.unwrap(), r#"{"field1":"Hello","field2":42}"#);
+
+        // Concurrent requests are multiplexed over the one connection
+        let (user, echo, stream) = tokio::join!(
+            client.get(format!("http://localhost:{port}/users/7?x=1")).send(),
+            client.post(format!("http://localhost:{port}/echo")).body("hello").send(),
+            client.get(format!("http://localhost:{port}/stream")).send()
+        );
+        assert_eq!(user.unwrap().text().await.unwrap(), "user 7");
+        assert_eq!(echo.unwrap().text().await.unwrap(), "hello|");
+        assert_eq!(stream.unwrap().text().await.unwrap(), "first second third");
+
+        let response = client.head(format!("http://localhost:{port}/")).send().await.unwrap();
+        assert_eq!(response.headers().get("Content-Length").unwrap(), "30");
+        assert_eq!(response.text().await.unwrap(), "");
+        let response = client.get(format!("http://localhost:{port}/missing")).send().await.unwrap();
+        assert_eq!(response.status(), 404);
+        let response = client.delete(format!("http://localhost:{port}/echo")).send().await.unwrap();
+        assert_eq!(response.status(), 405);
+        assert_eq!(response.headers().get("Allow").unwrap(), "POST, OPTIONS");
+        let response = client.post(format!("http://localhost:{port}/echo")).body("x".repeat(64)).send().await.unwrap();
+        assert_eq!(response.status(), 413);
+
+        // HTTP/1.1 keeps working on the same port
+        let response = reqwest::get(format!("http://localhost:{port}/users/8")).await.unwrap();
+        assert_eq!(response.version(), http::Version::HTTP_11);
+        assert_eq!(response.text().await.unwrap(), "user 8");
+    }
+
+    #[tokio::test]
+    async fn test_http2_alpn(){
+        use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+        use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+        use tokio_rustls::rustls::pki_types::pem::PemObject;
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        let (cert_path, key_path, cert_pem) = write_self_signed_cert("http2_alpn", "localhost");
+        server.enable_tls(&cert_path, &key_path).unwrap();
+        server.add_route("/users/:id", "GET", serve_user);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+
+        let mut roots = RootCertStore::empty();
+        roots.add(CertificateDer::from_pem_slice(&cert_pem).unwrap()).unwrap();
+        let mut config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+        let tcp = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let tls = connector.connect(ServerName::try_from("localhost").unwrap(), tcp).await.unwrap();
+        assert_eq!(tls.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+
+        let (mut sender, connection) = h2::client::handshake(tls).await.unwrap();
+        tokio::spawn(connection);
+        let request = http::Request::get(format!("https://localhost:{port}/users/3")).body(()).unwrap();
+        let (response, _) = sender.send_request(request, true).unwrap();
+        let response = response.await.unwrap();
+        assert_eq!(response.status(), 200);
+        let mut body = response.into_body();
+        let mut received = Vec::new();
+        while let Some(chunk) = body.data().await{
+            received.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(received, b"user 3");
+
+        // Clients that only offer HTTP/1.1 still get it
+        let client = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(&cert_pem).unwrap())
+            .build().unwrap();
+        let response = client.get(format!("https://localhost:{port}/users/4")).send().await.unwrap();
+        assert_eq!(response.version(), http::Version::HTTP_11);
+        assert_eq!(response.text().await.unwrap(), "user 4");
+    }
+
+    struct Greeter{
+        greeting: String
+    }
+
+    impl Handler for Greeter{
+        fn call(&self, request: Request, mut writer: ResponseWriter) -> AsyncReturn{
+            writer.set_status(HttpStatus::Ok);
+            writer.set_body(format!("{} {}", self.greeting, request.param("name").unwrap_or("stranger")));
+            writer.response()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stateful_handlers(){
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        server.add_route("/count", "POST", move |_request: Request, mut writer: ResponseWriter| {
+            let count = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            writer.set_status(HttpStatus::Ok);
+            writer.set_body(count.to_string());
+            writer.response()
+        });
+        server.add_route("/greet/:name", "GET", Greeter{greeting: "hello".to_string()});
+        let missing = Arc::new(String::from("nothing here"));
+        server.set_not_found_handler(move |_request: Request, mut writer: ResponseWriter| {
+            writer.set_status(HttpStatus::NotFound);
+            writer.set_body(missing.to_string());
+            writer.response()
+        });
+        // Plain #[api_callback] functions keep working next to closures
+        server.add_route("/", "GET", serve_get);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let client = reqwest::Client::new();
+        for expected in 1..=3{
+            let body = client.post(format!("http://localhost:{port}/count")).send().await.unwrap().text().await.unwrap();
+            assert_eq!(body, expected.to_string());
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+        let body = client.get(format!("http://localhost:{port}/greet/ana")).send().await.unwrap().text().await.unwrap();
+        assert_eq!(body, "hello ana");
+        let response = client.get(format!("http://localhost:{port}/missing")).send().await.unwrap();
+        assert_eq!(response.status(), 404);
+        assert_eq!(response.text().await.unwrap(), "nothing here");
+        let response = client.get(format!("http://localhost:{port}/")).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    struct AppState{
+        greeting: String
+    }
+
+    #[derive(serde::Deserialize)]
+    struct NewUser{
+        name: String,
+        age: u32
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Paging{
+        page: u32,
+        per_page: Option<u32>
+    }
+
+    #[api_callback]
+    pub fn create_team_user(State(app): State<AppState>, Path(team): Path<u32>, Query(paging): Query<Paging>, Json(user): Json<NewUser>, mut writer: ResponseWriter){
+        writer.set_status(HttpStatus::Created);
+        writer.set_body(format!("{} {} ({}) team {} page {}/{}", app.greeting, user.name, user.age, team, paging.page, paging.per_page.unwrap_or(10)));
+        writer.response()
+    }
+
+    #[api_callback]
+    pub fn submit_user_form(Form(user): Form<NewUser>, headers: HeaderMap, mut writer: ResponseWriter){
+        let client = headers.get("x-client").and_then(|value| value.to_str().ok()).unwrap_or("unknown");
+        writer.set_status(HttpStatus::Ok);
+        writer.set_body(format!("{} {} via {}", user.name, user.age, client));
+        writer.response()
+    }
+
+    #[api_callback]
+    pub fn serve_missing_state(State(count): State<u64>, mut writer: ResponseWriter){
+        writer.set_body(count.to_string());
+        writer.response()
+    }
+
+    #[tokio::test]
+    async fn test_extractors(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.with_state(AppState{greeting: "welcome".to_string()});
+        server.add_route("/teams/:team/users", "POST", create_team_user);
+        server.add_route("/form", "POST", submit_user_form);
+        server.add_route("/count", "GET", serve_missing_state);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let client = reqwest::Client::new();
+        let url = format!("http://localhost:{port}/teams/5/users?page=2");
+
+        let response = client.post(&url).header("Content-Type", "application/json")
+            .body(r#"{"name":"ana","age":31}"#).send().await.unwrap();
+        assert_eq!(response.status(), 201);
+        assert_eq!(response.text().await.unwrap(), "welcome ana (31) team 5 page 2/10");
+
+        let response = client.post(&url).header("Content-Type", "text/plain")
+            .body(r#"{"name":"ana","age":31}"#).send().await.unwrap();
+        assert_eq!(response.status(), 415);
+        let response = client.post(&url).header("Content-Type", "application/json")
+            .body(r#"{"name":"ana","#).send().await.unwrap();
+        assert_eq!(response.status(), 400);
+        let response = client.post(&url).header("Content-Type", "application/json")
+            .body(r#"{"name":"ana"}"#).send().await.unwrap();
+        assert_eq!(response.status(), 422);
+        assert!(response.text().await.unwrap().contains("missing field `age`"));
+        let response = client.post(format!("http://localhost:{port}/teams/5/users?page=two"))
+            .header("Content-Type", "application/json").body(r#"{"name":"ana","age":31}"#).send().await.unwrap();
+        assert_eq!(response.status(), 400);
+        let response = client.post(format!("http://localhost:{port}/teams/blue/users?page=1"))
+            .header("Content-Type", "application/json").body(r#"{"name":"ana","age":31}"#).send().await.unwrap();
+        assert_eq!(response.status(), 400);
+
+        let response = client.post(format!("http://localhost:{port}/form")).header("X-Client", "tests")
+            .form(&[("name", "bo b"), ("age", "40")]).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "bo b 40 via tests");
+        let response = client.post(format!("http://localhost:{port}/form"))
+            .header("Content-Type", "application/json").body(r#"{"name":"bob","age":40}"#).send().await.unwrap();
+        assert_eq!(response.status(), 415);
+        let response = client.post(format!("http://localhost:{port}/form"))
+            .form(&[("name", "bob"), ("age", "old")]).send().await.unwrap();
+        assert_eq!(response.status(), 422);
+
+        let response = client.get(format!("http://localhost:{port}/count")).send().await.unwrap();
+        assert_eq!(response.status(), 500);
+    }
+
+    #[api_callback]
+    pub async fn serve_async_user(Path(id): Path<u32>, mut writer: ResponseWriter){
+        writer.set_status(HttpStatus::Ok);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        writer.set_body(format!("user {id}"));
+        writer.response()
+    }
+
+    #[api_callback]
+    pub async fn serve_async_failure(_request: Request, mut writer: ResponseWriter){
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let contents = tokio::fs::read_to_string("./path_that_doesnt_exist.txt").await?;
+        writer.set_body(contents);
+        writer.response()
+    }
+
+    #[tokio::test]
+    async fn test_async_handlers(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_route("/users/:id", "GET", serve_async_user);
+        server.add_route("/failure", "GET", serve_async_failure);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let client = reqwest::Client::new();
+
+        // The test runtime has a single thread, so blocking handlers would run one after another
+        let started = std::time::Instant::now();
+        let responses = futures::future::join_all((1..=4).map(|id| {
+            client.get(format!("http://localhost:{port}/users/{id}")).send()
+        })).await;
+        assert!(started.elapsed() < Duration::from_millis(1000));
+        for (id, response) in (1..=4).zip(responses){
+            assert_eq!(response.unwrap().text().await.unwrap(), format!("user {id}"));
+        }
+
+        let response = client.get(format!("http://localhost:{port}/failure")).send().await.unwrap();
+        assert_eq!(response.status(), 500);
+    }
+
+    fn timing_middleware(request: Request, writer: ResponseWriter, next: Next) -> AsyncReturn{
+        let started = std::time::Instant::now();
+        let response = next.run(request, writer)?;
+        Ok(Box::pin(async move {
+            let mut response = response.await?;
+            response.headers.push(("X-Elapsed-Ms".to_string(), started.elapsed().as_millis().to_string()));
+            Ok(response)
+        }))
+    }
+
+    fn auth_middleware(mut request: Request, mut writer: ResponseWriter, next: Next) -> AsyncReturn{
+        if !request.request.path.starts_with("/private"){
+            return next.run(request, writer);
+        }
+        match request.request.header_value("Authorization").cloned(){
+            Some(token) => {
+                request.request.header.insert("X-User".to_string(), token.trim_start_matches("Bearer ").to_string());
+                next.run(request, writer)
+            }
+            None => {
+                writer.set_status(HttpStatus::Unauthorized);
+                writer.response()
+            }
+        }
+    }
+
+    #[api_callback]
+    pub fn serve_private(request: Request, mut writer: ResponseWriter){
+        writer.set_status(HttpStatus::Ok);
+        writer.set_body(format!("hello {}", request.request.header_value("X-User").unwrap()));
+        writer.response()
+    }
+
+    #[tokio::test]
+    async fn test_middleware(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_middleware(timing_middleware);
+        server.add_middleware(auth_middleware);
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = calls.clone();
+        server.add_middleware(move |request: Request, writer: ResponseWriter, next: Next| {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            next.run(request, writer)
+        });
+        server.add_route("/private", "GET", serve_private);
+        server.add_route("/", "GET", serve_get);
+        server.add_route("/error", "GET", file_not_exist);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let client = reqwest::Client::new();
+
+        let response = client.get(format!("http://localhost:{port}/private")).send().await.unwrap();
+        assert_eq!(response.status(), 401);
+        assert!(response.headers().get("X-Elapsed-Ms").is_some());
+        // Stopped by the auth middleware before the counting one ran
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 0);
+
+        let response = client.get(format!("http://localhost:{port}/private"))
+            .header("Authorization", "Bearer ana").send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().await.unwrap(), "hello ana");
+
+        for path in ["/", "/missing", "/error"]{
+            let response = client.get(format!("http://localhost:{port}{path}")).send().await.unwrap();
+            assert!(response.headers().get("X-Elapsed-Ms").is_some(), "no timing header for {path}");
+        }
+        let response = client.get(format!("http://localhost:{port}/error")).send().await.unwrap();
+        assert_eq!(response.status(), 500);
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 5);
+    }
+
+    fn group_middleware(request: Request, writer: ResponseWriter, next: Next) -> AsyncReturn{
+        let response = next.run(request, writer)?;
+        Ok(Box::pin(async move {
+            let mut response = response.await?;
+            response.headers.push(("X-Group".to_string(), "users".to_string()));
+            Ok(response)
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_websocket_middleware(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_middleware(auth_middleware);
+        server.add_websocket_route("/private/ws/:room", serve_echo_socket);
+        let mut group = Router::new();
+        group.add_middleware(auth_middleware);
+        group.add_websocket_route("/ws", serve_echo_socket);
+        server.mount("/private-group", group);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+
+        for path in ["/private/ws/lobby", "/private-group/ws"]{
+            let (_, head) = ws_connect(port, path).await;
+            assert!(head.starts_with("HTTP/1.1 401 Unauthorized"), "{path}");
+            let (_, head) = ws_connect_with(port, path, "Authorization: Bearer alice\r\n").await;
+            assert!(head.starts_with("HTTP/1.1 101 Switching Protocols"), "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mounted_routers(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_middleware(timing_middleware);
+        server.add_route("/api/v1/health", "GET", serve_get);
+
+        let mut users = Router::new();
+        users.add_route("/", "GET", serve_current_user);
+        users.add_route("/:id", "GET", serve_user);
+        users.add_middleware(group_middleware);
+        users.set_not_found_handler(serve_teapot);
+        let mut orders = Router::new();
+        orders.add_route("/:id/items/:post_id", "GET", serve_user_post);
+        let mut api = Router::new();
+        assert!(api.mount("/users", users));
+        assert!(api.mount("/orders", orders));
+        server.mount("/api/v1", api);
+
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let client = reqwest::Client::new();
+
+        let response = client.get(format!("http://localhost:{port}/api/v1/users/7")).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["X-Group"], "users");
+        assert!(response.headers().get("X-Elapsed-Ms").is_some());
+        assert_eq!(response.text().await.unwrap(), "user 7");
+
+        let response = client.get(format!("http://localhost:{port}/api/v1/users")).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "current user");
+
+        let response = client.get(format!("http://localhost:{port}/api/v1/orders/3/items/9")).send().await.unwrap();
+        assert!(response.headers().get("X-Group").is_none());
+        assert_eq!(response.text().await.unwrap(), "3/9");
+
+        // Unknown paths below the group use its 404 handler and middleware
+        let response = client.get(format!("http://localhost:{port}/api/v1/users/7/missing")).send().await.unwrap();
+        assert_eq!(response.status(), 418);
+        assert_eq!(response.headers()["X-Group"], "users");
+
+        let response = client.delete(format!("http://localhost:{port}/api/v1/users/7")).send().await.unwrap();
+        assert_eq!(response.status(), 405);
+        assert_eq!(response.headers()["Allow"], "GET, HEAD, OPTIONS");
+
+        let response = client.get(format!("http://localhost:{port}/api/v1/health")).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(response.headers().get("X-Group").is_none());
+
+        for path in ["/api/v1/orders/3", "/api/v2/users/7"]{
+            let response = client.get(format!("http://localhost:{port}{path}")).send().await.unwrap();
+            assert_eq!(response.status(), 404, "{path}");
+        }
+    }
+
+    #[test]
+    fn test_conflicting_mounts(){
+        let mut router = Router::new();
+        assert!(router.mount("/api", Router::new()));
+        assert!(!router.mount("/api", Router::new()));
+        assert!(!router.mount("/files/*rest", Router::new()));
+        assert!(router.mount("/teams/:team", Router::new()));
+        assert!(!router.mount("/teams/:id/users", Router::new()));
+    }
+
+    #[api_callback]
+    pub fn panic_in_handler(request: Request, mut writer: ResponseWriter){
+        let id : u32 = request.param("id").unwrap().parse().unwrap();
+        writer.set_body(id.to_string());
+        writer.response()
+    }
+
+    #[api_callback]
+    pub async fn panic_in_future(_request: Request, mut writer: ResponseWriter){
+        tokio::task::yield_now().await;
+        let values : Vec<u8> = Vec::new();
+        writer.set_body(values[1].to_string());
+        writer.response()
+    }
+
+    fn panicking_middleware(request: Request, writer: ResponseWriter, next: Next) -> AsyncReturn{
+        if request.request.path == "/middleware"{
+            panic!("middleware bug");
+        }
+        next.run(request, writer)
+    }
+
+    #[tokio::test]
+    async fn test_handler_panics(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_middleware(timing_middleware);
+        server.add_middleware(panicking_middleware);
+        server.add_route("/users/:id", "GET", panic_in_handler);
+        server.add_route("/future", "GET", panic_in_future);
+        server.add_route("/middleware", "GET", serve_get);
+        server.add_route("/", "GET", serve_get);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let client = reqwest::Client::new();
+
+        for path in ["/users/abc", "/future"]{
+            let response = client.get(format!("http://localhost:{port}{path}")).send().await.unwrap();
+            assert_eq!(response.status(), 500, "{path}");
+            // Caught inside the middleware stack, so the response still passes through it
+            assert!(response.headers().get("X-Elapsed-Ms").is_some(), "{path}");
+        }
+        let response = client.get(format!("http://localhost:{port}/middleware")).send().await.unwrap();
+        assert_eq!(response.status(), 500);
+
+        // The connection stays usable after a panic
+        let response = send_raw(port, b"GET /future HTTP/1.1\r\nHost: localhost\r\n\r\nGET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 500"), "{response}");
+        assert!(response.contains("HTTP/1.1 200"), "{response}");
+    }
+
+    #[api_callback]
+    pub fn serve_error_text(_request: Request, mut writer: ResponseWriter){
+        writer.set_body("Internal Server Error".to_string());
+        writer.response()
+    }
+
+    #[api_callback]
+    pub async fn serve_missing_order(request: Request, mut writer: ResponseWriter){
+        tokio::task::yield_now().await;
+        let id = request.param("id").unwrap();
+        if id != "1"{
+            return Err(HandlerError::new(HttpStatus::NotFound, format!("no order {id}")));
+        }
+        writer.response()
+    }
+
+    #[api_callback]
+    pub fn count_fields(Json(value): Json<HashMap<String, String>>, mut writer: ResponseWriter){
+        writer.set_body(value.len().to_string());
+        writer.response()
+    }
+
+    fn describe_error(request: Request, mut writer: ResponseWriter, error: HandlerError) -> AsyncReturn{
+        let source = match error.source(){
+            Some(source) if source.is::<std::io::Error>() => "io",
+            Some(_) => "other",
+            None => "none"
+        };
+        writer.set_status(error.status);
+        let mut body = format!("{source}: {}", error.message);
+        if let Some(id) = request.param("id"){
+            body.push_str(&format!(" (id {id})"));
+        }
+        writer.set_body(body);
+        writer.response()
+    }
+
+    #[tokio::test]
+    async fn test_error_handler(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.set_error_handler(describe_error);
+        server.add_route("/text", "GET", serve_error_text);
+        server.add_route("/file", "GET", file_not_exist);
+        server.add_route("/orders/:id", "GET", serve_missing_order);
+        server.add_route("/users/:id", "GET", panic_in_handler);
+        server.add_route("/fields", "POST", count_fields);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let client = reqwest::Client::new();
+
+        // A body that reads like an error is still a normal response
+        let response = client.get(format!("http://localhost:{port}/text")).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().await.unwrap(), "Internal Server Error");
+
+        let response = client.get(format!("http://localhost:{port}/file")).send().await.unwrap();
+        assert_eq!(response.status(), 500);
+        assert!(response.text().await.unwrap().starts_with("io: "));
+
+        let response = client.get(format!("http://localhost:{port}/orders/2")).send().await.unwrap();
+        assert_eq!(response.status(), 404);
+        assert_eq!(response.text().await.unwrap(), "none: no order 2 (id 2)");
+
+        let response = client.get(format!("http://localhost:{port}/users/abc")).send().await.unwrap();
+        assert_eq!(response.status(), 500);
+        let body = response.text().await.unwrap();
+        assert!(body.starts_with("none: handler panicked") && body.ends_with("(id abc)"));
+
+        // Extractor rejections go through the error handler with their status
+        let response = client.post(format!("http://localhost:{port}/fields")).body("{}").send().await.unwrap();
+        assert_eq!(response.status(), 415);
+        assert!(response.text().await.unwrap().starts_with("none: "));
+    }
+
+    #[tokio::test]
+    async fn test_internal_server_error_handler(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.set_internal_server_error_handler(serve_teapot);
+        server.add_route("/error", "GET", trigger_panic);
+        server.add_route("/orders/:id", "GET", serve_missing_order);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let client = reqwest::Client::new();
+        let response = client.get(format!("http://localhost:{port}/error")).send().await.unwrap();
+        assert_eq!(response.status(), 418);
+        // Client errors keep the default response
+        let response = client.get(format!("http://localhost:{port}/orders/2")).send().await.unwrap();
+        assert_eq!(response.status(), 404);
+        assert_eq!(response.text().await.unwrap(), "no order 2");
+    }
+
+    fn write_static_site(name: &str) -> std::path::PathBuf{
+        let base = std::env::temp_dir().join(format!("nashar_gah_{}", name));
+        let _ = std::fs::remove_dir_all(&base);
+        let root = base.join("public");
+        std::fs::create_dir_all(root.join("docs/more")).unwrap();
+        std::fs::create_dir_all(root.join("app")).unwrap();
+        std::fs::write(root.join("site.css"), "body{}").unwrap();
+        std::fs::write(root.join("docs/a&b <c>.txt"), "notes").unwrap();
+        std::fs::write(root.join("app/index.html"), "<h1>app</h1>").unwrap();
+        std::fs::write(base.join("secret.txt"), "secret").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(base.join("secret.txt"), root.join("escape.txt")).unwrap();
+        root
+    }
+
+    #[tokio::test]
+    async fn test_serve_dir(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        let root = write_static_site("serve_dir");
+        server.serve_dir("/static", ServeDir::new(&root).listing(true));
+        server.serve_dir("/plain/", ServeDir::new(&root));
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build().unwrap();
+
+        let response = client.get(format!("http://localhost:{port}/static/site.css")).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["Content-Type"], "text/css; charset=utf-8");
+        assert_eq!(response.text().await.unwrap(), "body{}");
+
+        let response = client.get(format!("http://localhost:{port}/static/docs/a%26b%20%3Cc%3E.txt")).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "notes");
+
+        let response = client.get(format!("http://localhost:{port}/static/app")).send().await.unwrap();
+        assert_eq!(response.status(), 301);
+        assert_eq!(response.headers()["Location"], "/static/app/");
+        let response = client.get(format!("http://localhost:{port}/static/app/")).send().await.unwrap();
+        assert_eq!(response.headers()["Content-Type"], "text/html; charset=utf-8");
+        assert_eq!(response.text().await.unwrap(), "<h1>app</h1>");
+
+        let response = client.get(format!("http://localhost:{port}/static/docs/")).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        let listing = response.text().await.unwrap();
+        assert!(listing.contains("<a href=\"../\">../</a>"));
+        assert!(listing.contains("<a href=\"more/\">more/</a>"));
+        assert!(listing.contains("<a href=\"a&amp;b%20%3Cc%3E.txt\">a&amp;b &lt;c&gt;.txt</a>"));
+        let response = client.get(format!("http://localhost:{port}/plain/docs/")).send().await.unwrap();
+        assert_eq!(response.status(), 404);
+
+        for path in ["/static/missing.css", "/static/escape.txt"]{
+            let response = client.get(format!("http://localhost:{port}{path}")).send().await.unwrap();
+            assert_eq!(response.status(), 404, "{path}");
+        }
+        let response = send_raw(port, b"GET /static/docs/..%2f..%2fsecret.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+        for target in ["/static/../secret.txt", "/static/%2e%2e/secret.txt", "/static/docs/%2e%2e/%2e%2e/secret.txt"]{
+            let request = format!("GET {target} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+            let response = send_raw(port, request.as_bytes()).await;
+            assert!(!response.contains("secret"), "{target} {response}");
+        }
+    }
+
+    #[api_callback]
+    pub fn update_document(request: Request, mut writer: ResponseWriter){
+        request.preconditions(Some("\"v1\""), None)?;
+        writer.set_status(HttpStatus::NoContent);
+        writer.response()
+    }
+
+    #[api_callback]
+    pub fn update_untagged_document(request: Request, mut writer: ResponseWriter){
+        request.preconditions(None, None)?;
+        writer.set_status(HttpStatus::NoContent);
+        writer.response()
+    }
+
+    #[tokio::test]
+    async fn test_conditional_requests(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        let root = write_static_site("conditional");
+        server.serve_dir("/static", ServeDir::new(&root));
+        server.add_route("/json", "GET", serve_json_payload);
+        server.add_route("/doc", "PUT", update_document);
+        server.add_route("/untagged", "PUT", update_untagged_document);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let client = reqwest::Client::new();
+
+        let response = client.get(format!("http://localhost:{port}/json")).send().await.unwrap();
+        let etag = response.headers()["ETag"].to_str().unwrap().to_string();
+        assert!(etag.starts_with('"'));
+        let response = client.get(format!("http://localhost:{port}/json")).send().await.unwrap();
+        assert_eq!(response.headers()["ETag"], etag.as_str());
+
+        for if_none_match in [etag.clone(), format!("\"other\", W/{etag}"), "*".to_string()]{
+            let response = client.get(format!("http://localhost:{port}/json"))
+                .header("If-None-Match", &if_none_match).send().await.unwrap();
+            assert_eq!(response.status(), 304, "{if_none_match}");
+            assert_eq!(response.headers()["ETag"], etag.as_str());
+            assert!(response.headers().get("Content-Type").is_none());
+            assert_eq!(response.text().await.unwrap(), "");
+        }
+        let response = client.get(format!("http://localhost:{port}/json"))
+            .header("If-Match", "\"other\"").send().await.unwrap();
+        assert_eq!(response.status(), 412);
+        assert_eq!(response.text().await.unwrap(), "");
+        let response = client.get(format!("http://localhost:{port}/json"))
+            .header("If-Match", format!("W/{etag}")).send().await.unwrap();
+        assert_eq!(response.status(), 412);
+
+        let response = client.get(format!("http://localhost:{port}/static/site.css")).send().await.unwrap();
+        let file_etag = response.headers()["ETag"].to_str().unwrap().to_string();
+        let last_modified = response.headers()["Last-Modified"].to_str().unwrap().to_string();
+        assert_ne!(file_etag, etag_for_bytes(b"body{}"));
+        let response = client.get(format!("http://localhost:{port}/static/site.css"))
+            .header("If-Modified-Since", &last_modified).send().await.unwrap();
+        assert_eq!(response.status(), 304);
+        assert_eq!(response.headers()["Last-Modified"], last_modified.as_str());
+        let response = client.get(format!("http://localhost:{port}/static/site.css"))
+            .header("If-Modified-Since", "Sat, 01 Jan 2000 00:00:00 GMT").send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().await.unwrap(), "body{}");
+        // If-None-Match wins over If-Modified-Since
+        let response = client.get(format!("http://localhost:{port}/static/site.css"))
+            .header("If-None-Match", "\"stale\"")
+            .header("If-Modified-Since", &last_modified).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        let response = client.head(format!("http://localhost:{port}/static/site.css"))
+            .header("If-Unmodified-Since", "Sat, 01 Jan 2000 00:00:00 GMT").send().await.unwrap();
+        assert_eq!(response.status(), 412);
+
+        let response = client.put(format!("http://localhost:{port}/doc"))
+            .header("If-Match", "\"v1\"").send().await.unwrap();
+        assert_eq!(response.status(), 204);
+        let response = client.put(format!("http://localhost:{port}/doc"))
+            .header("If-Match", "\"v0\"").send().await.unwrap();
+        assert_eq!(response.status(), 412);
+        // `*` only asks for the resource to exist, which it does without an ETag too
+        let response = client.put(format!("http://localhost:{port}/untagged"))
+            .header("If-Match", "*").send().await.unwrap();
+        assert_eq!(response.status(), 204);
+    }
+
+    #[tokio::test]
+    async fn test_range_requests(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        let root = write_static_site("range");
+        std::fs::write(root.join("digits.txt"), "0123456789").unwrap();
+        server.serve_dir("/static", ServeDir::new(&root));
+        server.add_route("/text", "GET", serve_error_text);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let client = reqwest::Client::new();
+
+        for path in ["/static/digits.txt", "/text"]{
+            let response = client.head(format!("http://localhost:{port}{path}")).send().await.unwrap();
+            assert_eq!(response.headers()["Accept-Ranges"], "bytes", "{path}");
+        }
+        for (range, body, content_range) in [("bytes=2-4", "234", "bytes 2-4/10"), ("bytes=-3", "789", "bytes 7-9/10"), ("bytes=7-", "789", "bytes 7-9/10"), ("bytes=0-2,1-4", "01234", "bytes 0-4/10"), ("bytes=8-100", "89", "bytes 8-9/10")]{
+            let response = client.get(format!("http://localhost:{port}/static/digits.txt"))
+                .header("Range", range).send().await.unwrap();
+            assert_eq!(response.status(), 206, "{range}");
+            assert_eq!(response.headers()["Content-Range"], content_range, "{range}");
+            assert_eq!(response.text().await.unwrap(), body, "{range}");
+        }
+
+        let response = client.get(format!("http://localhost:{port}/static/digits.txt"))
+            .header("Range", "bytes=0-1, 5-6").send().await.unwrap();
+        assert_eq!(response.status(), 206);
+        let content_type = response.headers()["Content-Type"].to_str().unwrap().to_string();
+        let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").unwrap();
+        let expected = format!("\r\n--{boundary}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Range: bytes 0-1/10\r\n\r\n01\r\n--{boundary}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Range: bytes 5-6/10\r\n\r\n56\r\n--{boundary}--\r\n");
+        assert_eq!(response.text().await.unwrap(), expected);
+
+        let response = client.get(format!("http://localhost:{port}/static/digits.txt"))
+            .header("Range", "bytes=20-30").send().await.unwrap();
+        assert_eq!(response.status(), 416);
+        assert_eq!(response.headers()["Content-Range"], "bytes */10");
+        for range in ["bytes=abc", "bytes=5-2", "items=0-1"]{
+            let response = client.get(format!("http://localhost:{port}/static/digits.txt"))
+                .header("Range", range).send().await.unwrap();
+            assert_eq!(response.status(), 200, "{range}");
+            assert_eq!(response.text().await.unwrap(), "0123456789");
+        }
+
+        let response = client.get(format!("http://localhost:{port}/text")).send().await.unwrap();
+        let etag = response.headers()["ETag"].to_str().unwrap().to_string();
+        let response = client.get(format!("http://localhost:{port}/text"))
+            .header("Range", "bytes=0-7").header("If-Range", &etag).send().await.unwrap();
+        assert_eq!(response.status(), 206);
+        assert_eq!(response.headers()["Content-Range"], "bytes 0-7/21");
+        assert_eq!(response.text().await.unwrap(), "Internal");
+        let response = client.get(format!("http://localhost:{port}/text"))
+            .header("Range", "bytes=0-7").header("If-Range", "\"stale\"").send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().await.unwrap(), "Internal Server Error");
+    }
+
+    fn large_json() -> String{
+        let items : Vec<String> = (0..200).map(|id| format!(r#"{{"id": {id}, "name": "item"}}"#)).collect();
+        format!("[{}]", items.join(","))
+    }
+
+    #[api_callback]
+    pub fn serve_large_json(_request: Request, mut writer: ResponseWriter){
+        writer.set_body(large_json());
+        writer.set_content_type("application/json".to_string());
+        writer.response()
+    }
+
+    #[api_callback]
+    pub fn serve_large_png(_request: Request, mut writer: ResponseWriter){
+        writer.set_body_bytes(vec![0; 4096]);
+        writer.set_content_type("image/png".to_string());
+        writer.response()
+    }
+
+    fn decode(encoding: &str, body: &[u8]) -> String{
+        use std::io::Read;
+        let mut decoded = String::new();
+        match encoding{
+            "gzip" => flate2::read::GzDecoder::new(body).read_to_string(&mut decoded).unwrap(),
+            "br" => brotli::Decompressor::new(body, 4096).read_to_string(&mut decoded).unwrap(),
+            "zstd" => zstd::stream::read::Decoder::new(body).unwrap().read_to_string(&mut decoded).unwrap(),
+            _ => panic!("unexpected encoding {encoding}")
+        };
+        decoded
+    }
+
+    #[tokio::test]
+    async fn test_compression(){
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.add_route("/json", "GET", serve_large_json);
+        server.add_route("/png", "GET", serve_large_png);
+        server.add_route("/small", "GET", serve_error_text);
+        server.add_route("/stream", "GET", serve_stream);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let client = reqwest::Client::new();
+
+        for (accept, encoding) in [("gzip", "gzip"), ("gzip, deflate, br, zstd", "br"), ("gzip;q=0.5, zstd", "zstd"), ("*", "br"), ("br;q=0, *;q=0.1, gzip;q=0.8", "gzip")]{
+            let response = client.get(format!("http://localhost:{port}/json"))
+                .header("Accept-Encoding", accept).send().await.unwrap();
+            assert_eq!(response.headers()["Content-Encoding"], encoding, "{accept}");
+            assert_eq!(response.headers()["Vary"], "Accept-Encoding");
+            assert!(response.headers()["ETag"].to_str().unwrap().starts_with("W/"));
+            let body = response.bytes().await.unwrap();
+            assert!(body.len() < large_json().len());
+            assert_eq!(decode(encoding, &body), large_json(), "{accept}");
+        }
+
+        // Nothing acceptable, or not worth compressing
+        for (path, accept) in [("/json", "identity"), ("/json", "br;q=0, gzip;q=0, zstd;q=0"), ("/png", "gzip"), ("/small", "gzip")]{
+            let response = client.get(format!("http://localhost:{port}{path}"))
+                .header("Accept-Encoding", accept).send().await.unwrap();
+            assert!(response.headers().get("Content-Encoding").is_none(), "{path} {accept}");
+        }
+        let response = client.get(format!("http://localhost:{port}/json")).send().await.unwrap();
+        assert_eq!(response.headers()["Vary"], "Accept-Encoding");
+        assert_eq!(response.text().await.unwrap(), large_json());
+
+        for encoding in ["gzip", "br", "zstd"]{
+            let response = client.get(format!("http://localhost:{port}/stream"))
+                .header("Accept-Encoding", encoding).send().await.unwrap();
+            assert_eq!(response.headers()["Content-Encoding"], encoding);
+            assert_eq!(response.headers()["Transfer-Encoding"], "chunked");
+            assert_eq!(decode(encoding, &response.bytes().await.unwrap()), "first second third");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_decompression(){
+        use std::io::Write;
+        let port = fetch_port().await;
+        let mut server = init_server(port);
+        server.set_max_body_size(512);
+        server.add_route("/", "POST", echo_body);
+        let _ = tokio::spawn(async move {
+            server.serve().await.unwrap();
+        });
+        tokio::task::yield_now().await;
+        let client = reqwest::Client::new();
+        let h2_client = reqwest::Client::builder().http2_prior_knowledge().build().unwrap();
+
+        let mut deflate = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        deflate.write_all(b"hello deflate").unwrap();
+        let gzip_then_br = Encoding::Brotli.compress(&Encoding::Gzip.compress(b"hello twice").unwrap()).unwrap();
+        for (content_encoding, body, expected) in [
+            ("gzip", Encoding::Gzip.compress(b"hello gzip").unwrap(), "hello gzip|"),
+            ("br", Encoding::Brotli.compress(b"hello br").unwrap(), "hello br|"),
+            ("ZSTD", Encoding::Zstd.compress(b"hello zstd").unwrap(), "hello zstd|"),
+            ("deflate", deflate.finish().unwrap(), "hello deflate|"),
+            ("gzip, br", gzip_then_br, "hello twice|"),
+            ("identity", b"plain".to_vec(), "plain|")
+        ]{
+            for client in [&client, &h2_client]{
+                let response = client.post(format!("http://localhost:{port}/"))
+                    .header("Content-Encoding", content_encoding).body(body.clone()).send().await.unwrap();
+                assert_eq!(response.status(), 200, "{content_encoding}");
+                assert_eq!(response.text().await.unwrap(), expected, "{content_encoding}");
+            }
+        }
+
+        // Small on the wire, far past the limit once decoded
+        let bomb = Encoding::Gzip.compress(&[0; 200_000]).unwrap();
+        assert!(bomb.len() < 512);
+        for (content_encoding, body, status) in [("gzip", bomb, 413), ("compress", b"data".to_vec(), 415), ("gzip", b"not gzip".to_vec(), 400)]{
+            for client in [&client, &h2_client]{
+                let response = client.post(format!("http://localhost:{port}/"))
+                    .header("Content-Encoding", content_encoding).body(body.clone()).send().await.unwrap();
+                assert_eq!(response.status(), status, "{content_encoding}");
+            }
+        }
+    }
+
     #[tokio::test]
     async fn test_init() {
         let port = 8080;
@@ -1,14 +1,268 @@
-use tokio::net::TcpListener;
-use tokio_rustls::rustls::{ClientConfig, RootCertStore};
-use tokio_rustls::TlsConnector;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use anyhow::{Error, Result};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::crypto::aws_lc_rs::sign::any_supported_type;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
 
 // https://crates.io/crates/tokio-rustls
 
+#[derive(Clone)]
 pub struct TLS{
-    pub listener : TcpListener
+    pub acceptor : TlsAcceptor,
+    pub certificates: CertificateStore
 }
 
 impl TLS{
-    fn new(){
+    pub fn new(cert_path: &str, key_path: &str) -> Result<Self, Error>{
+        let certificates = CertificateStore::new();
+        certificates.set_default_files(cert_path, key_path)?;
+        Ok(TLS::with_store(certificates))
     }
-}
\ No newline at end of file
+
+    /// Builds the acceptor from a PEM certificate chain (leaf first) and a
+    /// PEM private key in PKCS#1, PKCS#8 or SEC1 form.
+    pub fn from_pem(cert: &[u8], key: &[u8]) -> Result<Self, Error>{
+        let certificates = CertificateStore::new();
+        certificates.set_default_pem(cert, key)?;
+        Ok(TLS::with_store(certificates))
+    }
+
+    /// Certificates are picked per handshake from `certificates`, so changes
+    /// to the store apply to new connections only.
+    pub fn with_store(certificates: CertificateStore) -> Self{
+        let mut config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(certificates.clone()));
+        // Offered in order of preference, clients without ALPN get HTTP/1.1
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        TLS{acceptor, certificates}
+    }
+
+    pub async fn accept(&self, stream: TcpStream) -> Result<TlsStream<TcpStream>, Error>{
+        self.acceptor.accept(stream).await.map_err(Error::from)
+    }
+}
+
+impl fmt::Debug for TLS{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TLS")
+            .field("certificates", &self.certificates)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Shared set of certificates keyed by SNI hostname. Cloning hands out
+/// another handle to the same set, which is how certificates get swapped
+/// while the server is running.
+#[derive(Clone, Default)]
+pub struct CertificateStore{
+    inner: Arc<RwLock<CertificateSet>>
+}
+
+#[derive(Default)]
+struct CertificateSet{
+    default: Option<CertificateEntry>,
+    hosts: HashMap<String, CertificateEntry>
+}
+
+struct CertificateEntry{
+    key: Arc<CertifiedKey>,
+    source: Option<CertificateSource>
+}
+
+// Files a certificate was loaded from, with the mtimes seen at load time
+#[derive(Clone)]
+struct CertificateSource{
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    modified: (Option<SystemTime>, Option<SystemTime>)
+}
+
+impl CertificateSource{
+    fn new(cert_path: &Path, key_path: &Path) -> Self{
+        let mut source = CertificateSource{
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            modified: (None, None)
+        };
+        source.modified = source.current_mtimes();
+        source
+    }
+
+    fn current_mtimes(&self) -> (Option<SystemTime>, Option<SystemTime>){
+        let mtime = |path: &Path| std::fs::metadata(path).and_then(|meta| meta.modified()).ok();
+        (mtime(&self.cert_path), mtime(&self.key_path))
+    }
+
+    fn load(&self) -> Result<CertificateEntry, Error>{
+        let source = CertificateSource::new(&self.cert_path, &self.key_path);
+        let cert = std::fs::read(&self.cert_path)?;
+        let key = std::fs::read(&self.key_path)?;
+        let key = CertificateStore::certified_key(&cert, &key)?;
+        Ok(CertificateEntry{key, source: Some(source)})
+    }
+}
+
+impl CertificateStore{
+    pub fn new() -> Self{
+        CertificateStore::default()
+    }
+
+    /// Certificate used when the client sends no SNI or an unknown name.
+    pub fn set_default_pem(&self, cert: &[u8], key: &[u8]) -> Result<(), Error>{
+        let key = CertificateStore::certified_key(cert, key)?;
+        self.inner.write().unwrap().default = Some(CertificateEntry{key, source: None});
+        Ok(())
+    }
+
+    pub fn set_default_files(&self, cert_path: impl AsRef<Path>, key_path: impl AsRef<Path>) -> Result<(), Error>{
+        let entry = CertificateSource::new(cert_path.as_ref(), key_path.as_ref()).load()?;
+        self.inner.write().unwrap().default = Some(entry);
+        Ok(())
+    }
+
+    /// Adds or replaces the certificate for `hostname`. A leading `*.`
+    /// registers a wildcard covering one extra label.
+    pub fn insert_pem(&self, hostname: &str, cert: &[u8], key: &[u8]) -> Result<(), Error>{
+        let key = CertificateStore::certified_key(cert, key)?;
+        let entry = CertificateEntry{key, source: None};
+        self.inner.write().unwrap().hosts.insert(normalize_hostname(hostname), entry);
+        Ok(())
+    }
+
+    pub fn insert_files(&self, hostname: &str, cert_path: impl AsRef<Path>, key_path: impl AsRef<Path>) -> Result<(), Error>{
+        let entry = CertificateSource::new(cert_path.as_ref(), key_path.as_ref()).load()?;
+        self.inner.write().unwrap().hosts.insert(normalize_hostname(hostname), entry);
+        Ok(())
+    }
+
+    pub fn remove(&self, hostname: &str) -> bool{
+        self.inner.write().unwrap().hosts.remove(&normalize_hostname(hostname)).is_some()
+    }
+
+    pub fn hostnames(&self) -> Vec<String>{
+        let mut hostnames : Vec<String> = self.inner.read().unwrap().hosts.keys().cloned().collect();
+        hostnames.sort();
+        hostnames
+    }
+
+    /// Re-reads every file backed certificate whose files changed on disk
+    /// and returns how many were swapped. A certificate that fails to load
+    /// keeps serving the previous version.
+    pub fn reload_changed(&self) -> usize{
+        let mut changed = Vec::new();
+        {
+            let set = self.inner.read().unwrap();
+            let entries = set.default.iter().map(|entry| (None, entry))
+                .chain(set.hosts.iter().map(|(name, entry)| (Some(name.clone()), entry)));
+            for (name, entry) in entries{
+                if let Some(source) = &entry.source{
+                    if source.current_mtimes() != source.modified{
+                        changed.push((name, source.clone()));
+                    }
+                }
+            }
+        }
+
+        let mut reloaded = 0;
+        for (name, source) in changed{
+            let entry = match source.load(){
+                Ok(entry) => entry,
+                Err(err) => {
+                    println!("Failed to reload certificate {:?} {}", source.cert_path, err);
+                    // Remember these mtimes so a broken file is only reported once
+                    let mut set = self.inner.write().unwrap();
+                    let entry = match &name{
+                        Some(name) => set.hosts.get_mut(name),
+                        None => set.default.as_mut()
+                    };
+                    if let Some(source) = entry.and_then(|entry| entry.source.as_mut()){
+                        source.modified = source.current_mtimes();
+                    }
+                    continue
+                }
+            };
+            let mut set = self.inner.write().unwrap();
+            match name{
+                Some(name) => { set.hosts.insert(name, entry); }
+                None => { set.default = Some(entry); }
+            }
+            reloaded += 1;
+        }
+        reloaded
+    }
+
+    /// Polls the certificate files every `interval` and reloads the ones
+    /// that changed, e.g. after an ACME client renewed them.
+    pub fn watch(&self, interval: Duration) -> JoinHandle<()>{
+        let store = self.clone();
+        tokio::spawn(async move{
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop{
+                ticker.tick().await;
+                store.reload_changed();
+            }
+        })
+    }
+
+    fn certified_key(cert: &[u8], key: &[u8]) -> Result<Arc<CertifiedKey>, Error>{
+        let certs = CertificateDer::pem_slice_iter(cert)
+            .collect::<Result<Vec<_>, _>>()?;
+        if certs.is_empty(){
+            return Err(Error::msg("No certificate found in PEM data"));
+        }
+        let key = PrivateKeyDer::from_pem_slice(key)?;
+        let signing_key = any_supported_type(&key)?;
+        Ok(Arc::new(CertifiedKey::new(certs, signing_key)))
+    }
+
+    fn lookup(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>>{
+        let set = self.inner.read().unwrap();
+        if let Some(name) = server_name{
+            let name = normalize_hostname(name);
+            if let Some(entry) = set.hosts.get(&name){
+                return Some(entry.key.clone());
+            }
+            if let Some((_, parent)) = name.split_once('.'){
+                if let Some(entry) = set.hosts.get(&format!("*.{}", parent)){
+                    return Some(entry.key.clone());
+                }
+            }
+        }
+        set.default.as_ref().map(|entry| entry.key.clone())
+    }
+}
+
+impl ResolvesServerCert for CertificateStore{
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>>{
+        self.lookup(client_hello.server_name())
+    }
+}
+
+impl fmt::Debug for CertificateStore{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let set = self.inner.read().unwrap();
+        f.debug_struct("CertificateStore")
+            .field("default", &set.default.is_some())
+            .field("hosts", &set.hosts.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+fn normalize_hostname(hostname: &str) -> String{
+    hostname.trim_end_matches('.').to_ascii_lowercase()
+}
@@ -0,0 +1,373 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::parser::Parser;
+use super::http_status::HttpStatus;
+use super::server::Connection;
+
+// Appended to Sec-WebSocket-Key before hashing, fixed by RFC 6455
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 8 * 1024 * 1024;
+
+// Control frames may not carry more than this
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CloseFrame{
+    pub code: u16,
+    pub reason: String
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message{
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<CloseFrame>)
+}
+
+#[derive(Debug)]
+pub enum WsError{
+    Io(io::Error),
+    Protocol(&'static str),
+    TooLarge,
+    InvalidUtf8,
+    // A close frame was already sent, nothing else may follow it
+    Closed
+}
+
+impl fmt::Display for WsError{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self{
+            WsError::Io(err) => write!(f, "WebSocket io error: {}", err),
+            WsError::Protocol(reason) => write!(f, "WebSocket protocol error: {}", reason),
+            WsError::TooLarge => write!(f, "WebSocket message exceeds the size limit"),
+            WsError::InvalidUtf8 => write!(f, "WebSocket text message is not valid UTF-8"),
+            WsError::Closed => write!(f, "WebSocket is closed")
+        }
+    }
+}
+
+impl std::error::Error for WsError{}
+
+impl From<io::Error> for WsError{
+    fn from(err: io::Error) -> Self{
+        WsError::Io(err)
+    }
+}
+
+impl WsError{
+    fn close_code(&self) -> Option<u16>{
+        match self{
+            WsError::Protocol(_) => Some(CLOSE_PROTOCOL_ERROR),
+            WsError::TooLarge => Some(CLOSE_TOO_BIG),
+            WsError::InvalidUtf8 => Some(CLOSE_INVALID_DATA),
+            WsError::Io(_) | WsError::Closed => None
+        }
+    }
+}
+
+struct Frame{
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>
+}
+
+/// True for a `GET` asking to switch the connection to the WebSocket protocol.
+pub fn is_upgrade_request(parser: &Parser) -> bool{
+    let has_token = |name: &str, token: &str| {
+        parser.header_value(name)
+            .map(|value| value.split(',').any(|part| part.trim().eq_ignore_ascii_case(token)))
+            .unwrap_or(false)
+    };
+    parser.method == "GET" && has_token("Upgrade", "websocket") && has_token("Connection", "upgrade")
+}
+
+/// Checks the handshake headers and returns the `Sec-WebSocket-Accept` value.
+pub fn handshake(parser: &Parser) -> Result<String, HttpStatus>{
+    if parser.version == 0{
+        return Err(HttpStatus::BadRequest);
+    }
+    match parser.header_value("Sec-WebSocket-Version"){
+        Some(version) if version.trim() == "13" => {},
+        _ => return Err(HttpStatus::UpgradeRequired)
+    }
+    let key = parser.header_value("Sec-WebSocket-Key").ok_or(HttpStatus::BadRequest)?.trim();
+    match STANDARD.decode(key){
+        Ok(nonce) if nonce.len() == 16 => Ok(accept_key(key)),
+        _ => Err(HttpStatus::BadRequest)
+    }
+}
+
+pub fn accept_key(key: &str) -> String{
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(HANDSHAKE_GUID.as_bytes());
+    STANDARD.encode(hasher.finalize())
+}
+
+/// Server side of an upgraded connection. Pings are answered automatically
+/// and a close frame from the client is echoed before `recv` reports it.
+pub struct WebSocket{
+    stream: Box<dyn Connection>,
+    pub address: SocketAddr,
+    buffer: Vec<u8>,
+    max_message_size: usize,
+    // Opcode and payload of a fragmented message still being received
+    fragments: Option<(u8, Vec<u8>)>,
+    close_sent: bool,
+    close_received: bool
+}
+
+impl fmt::Debug for WebSocket{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WebSocket").field("address", &self.address).finish_non_exhaustive()
+    }
+}
+
+impl WebSocket{
+    /// `buffer` holds bytes the client sent right after the handshake.
+    pub fn new(stream: Box<dyn Connection>, address: SocketAddr, buffer: Vec<u8>, max_message_size: usize) -> Self{
+        WebSocket{
+            stream,
+            address,
+            buffer,
+            max_message_size,
+            fragments: None,
+            close_sent: false,
+            close_received: false
+        }
+    }
+
+    pub fn set_max_message_size(&mut self, max_message_size: usize){
+        self.max_message_size = max_message_size;
+    }
+
+    /// Next message from the client, `None` once the connection is closed.
+    /// Errors close the connection with the matching status code.
+    pub async fn recv(&mut self) -> Option<Result<Message, WsError>>{
+        if self.close_received{
+            return None;
+        }
+        match self.next_message().await{
+            Ok(Some(message)) => Some(Ok(message)),
+            Ok(None) => None,
+            Err(err) => {
+                if let Some(code) = err.close_code(){
+                    let _ = self.close(code, "").await;
+                }
+                self.close_received = true;
+                Some(Err(err))
+            }
+        }
+    }
+
+    pub async fn send(&mut self, message: Message) -> Result<(), WsError>{
+        if self.close_sent{
+            return Err(WsError::Closed);
+        }
+        let (opcode, payload) = match message{
+            Message::Text(text) => (OPCODE_TEXT, text.into_bytes()),
+            Message::Binary(data) => (OPCODE_BINARY, data),
+            Message::Ping(data) => (OPCODE_PING, data),
+            Message::Pong(data) => (OPCODE_PONG, data),
+            Message::Close(frame) => {
+                let mut payload = Vec::new();
+                if let Some(frame) = frame{
+                    payload.extend_from_slice(&frame.code.to_be_bytes());
+                    payload.extend_from_slice(frame.reason.as_bytes());
+                }
+                (OPCODE_CLOSE, payload)
+            }
+        };
+        if opcode >= OPCODE_CLOSE && payload.len() > MAX_CONTROL_PAYLOAD{
+            return Err(WsError::Protocol("control frame payload too long"));
+        }
+        if opcode == OPCODE_CLOSE{
+            self.close_sent = true;
+        }
+        self.write_frame(opcode, &payload).await
+    }
+
+    pub async fn close(&mut self, code: u16, reason: &str) -> Result<(), WsError>{
+        self.send(Message::Close(Some(CloseFrame{code, reason: reason.to_string()}))).await
+    }
+
+    async fn next_message(&mut self) -> Result<Option<Message>, WsError>{
+        loop{
+            let frame = match self.read_frame().await?{
+                Some(frame) => frame,
+                None => return Ok(None)
+            };
+            match frame.opcode{
+                OPCODE_TEXT | OPCODE_BINARY => {
+                    if self.fragments.is_some(){
+                        return Err(WsError::Protocol("new message before the last one finished"));
+                    }
+                    if frame.fin{
+                        return WebSocket::into_message(frame.opcode, frame.payload).map(Some);
+                    }
+                    self.fragments = Some((frame.opcode, frame.payload));
+                }
+                OPCODE_CONTINUATION => {
+                    let (opcode, mut payload) = self.fragments.take()
+                        .ok_or(WsError::Protocol("continuation without a message"))?;
+                    payload.extend_from_slice(&frame.payload);
+                    if frame.fin{
+                        return WebSocket::into_message(opcode, payload).map(Some);
+                    }
+                    self.fragments = Some((opcode, payload));
+                }
+                OPCODE_PING => {
+                    if !self.close_sent{
+                        self.write_frame(OPCODE_PONG, &frame.payload).await?;
+                    }
+                    return Ok(Some(Message::Ping(frame.payload)));
+                }
+                OPCODE_PONG => return Ok(Some(Message::Pong(frame.payload))),
+                OPCODE_CLOSE => {
+                    let close = WebSocket::parse_close(&frame.payload)?;
+                    self.close_received = true;
+                    if !self.close_sent{
+                        self.send(Message::Close(close.clone())).await?;
+                    }
+                    return Ok(Some(Message::Close(close)));
+                }
+                _ => return Err(WsError::Protocol("unknown opcode"))
+            }
+        }
+    }
+
+    fn into_message(opcode: u8, payload: Vec<u8>) -> Result<Message, WsError>{
+        if opcode == OPCODE_TEXT{
+            return String::from_utf8(payload).map(Message::Text).map_err(|_| WsError::InvalidUtf8);
+        }
+        Ok(Message::Binary(payload))
+    }
+
+    fn parse_close(payload: &[u8]) -> Result<Option<CloseFrame>, WsError>{
+        match payload.len(){
+            0 => Ok(None),
+            1 => Err(WsError::Protocol("close frame with a truncated code")),
+            _ => {
+                let code = u16::from_be_bytes([payload[0], payload[1]]);
+                if code < 1000 || (1004..=1006).contains(&code) || code == 1015{
+                    return Err(WsError::Protocol("invalid close code"));
+                }
+                let reason = String::from_utf8(payload[2..].to_vec()).map_err(|_| WsError::InvalidUtf8)?;
+                Ok(Some(CloseFrame{code, reason}))
+            }
+        }
+    }
+
+    // `None` when the client closed the socket between frames
+    async fn read_frame(&mut self) -> Result<Option<Frame>, WsError>{
+        if !self.fill(2).await?{
+            return Ok(None);
+        }
+        let fin = self.buffer[0] & 0x80 != 0;
+        if self.buffer[0] & 0x70 != 0{
+            return Err(WsError::Protocol("reserved bits set"));
+        }
+        let opcode = self.buffer[0] & 0x0F;
+        if self.buffer[1] & 0x80 == 0{
+            return Err(WsError::Protocol("client frames must be masked"));
+        }
+        let (length, mut offset) = match self.buffer[1] & 0x7F{
+            126 => {
+                self.fill_or_fail(4).await?;
+                (u16::from_be_bytes([self.buffer[2], self.buffer[3]]) as u64, 4)
+            }
+            127 => {
+                self.fill_or_fail(10).await?;
+                let mut length = [0; 8];
+                length.copy_from_slice(&self.buffer[2..10]);
+                (u64::from_be_bytes(length), 10)
+            }
+            length => (length as u64, 2)
+        };
+
+        if opcode >= OPCODE_CLOSE{
+            if !fin || length > MAX_CONTROL_PAYLOAD as u64{
+                return Err(WsError::Protocol("invalid control frame"));
+            }
+        }
+        else{
+            let buffered = self.fragments.as_ref().map(|(_, payload)| payload.len()).unwrap_or(0);
+            // The limit may have been lowered while a message was being buffered
+            if length > self.max_message_size.saturating_sub(buffered) as u64{
+                return Err(WsError::TooLarge);
+            }
+        }
+        let length = length as usize;
+
+        self.fill_or_fail(offset + 4 + length).await?;
+        let mut mask = [0; 4];
+        mask.copy_from_slice(&self.buffer[offset..offset + 4]);
+        offset += 4;
+        let mut payload : Vec<u8> = self.buffer.drain(..offset + length).skip(offset).collect();
+        for (index, byte) in payload.iter_mut().enumerate(){
+            *byte ^= mask[index % 4];
+        }
+        Ok(Some(Frame{fin, opcode, payload}))
+    }
+
+    // Reads until `len` bytes are buffered, false if the socket closed first
+    async fn fill(&mut self, len: usize) -> Result<bool, WsError>{
+        let mut temp_buffer = [0; 4096];
+        while self.buffer.len() < len{
+            let read = self.stream.read(&mut temp_buffer).await?;
+            if read == 0{
+                return Ok(false);
+            }
+            self.buffer.extend_from_slice(&temp_buffer[..read]);
+        }
+        Ok(true)
+    }
+
+    async fn fill_or_fail(&mut self, len: usize) -> Result<(), WsError>{
+        if !self.fill(len).await?{
+            return Err(WsError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed mid frame")));
+        }
+        Ok(())
+    }
+
+    // Server frames are never masked or fragmented
+    async fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<(), WsError>{
+        let mut frame = vec![0x80 | opcode];
+        match payload.len(){
+            len if len < 126 => frame.push(len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        self.stream.write_all(&frame).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+}
@@ -1,3 +1,7 @@
+// Lets macro expansions name `::nashar_gah` from inside this crate too
+extern crate self as nashar_gah;
+
 pub mod khadim;
 pub use meta_tags::api_callback;
-pub use meta_tags::init;
\ No newline at end of file
+pub use meta_tags::init;
+pub use meta_tags::ws_callback;
@@ -1,6 +1,6 @@
 use proc_macro::TokenStream;
 use quote::quote;
-use syn::{parse_macro_input, ItemFn};
+use syn::{parse_macro_input, FnArg, ItemFn, Type};
 
 #[proc_macro_attribute]
 pub fn api_callback(_attr: TokenStream, input: TokenStream) -> TokenStream {
@@ -10,10 +10,34 @@ pub fn api_callback(_attr: TokenStream, input: TokenStream) -> TokenStream {
     let name = &input_fn.sig.ident;
     let body = &input_fn.block;
 
-    // Generate the transformed function
+    // `request` and `writer` are always in scope, any other argument is
+    // extracted from the request by its type
+    let extractors = input_fn.sig.inputs.iter().filter_map(|arg| {
+        let arg = match arg {
+            FnArg::Typed(arg) => arg,
+            FnArg::Receiver(_) => return None
+        };
+        if is_type(&arg.ty, "Request") || is_type(&arg.ty, "ResponseWriter") {
+            return None;
+        }
+        let pat = &arg.pat;
+        let ty = &arg.ty;
+        Some(quote! {
+            let #pat: #ty = <#ty as ::nashar_gah::khadim::extract::FromRequest>::from_request(&request)?;
+        })
+    });
 
-    let transformed_fn = quote! {
-        pub fn #name<'a>(request: Request, mut writer: ResponseWriter<'a>) -> Result<std::pin::Pin<std::boxed::Box<dyn std::future::Future<Output = String> + Send>> , Box<dyn std::error::Error>> {
+    // An async body runs as part of the returned future, so it can await
+    // while holding the writer
+    let run = if input_fn.sig.asyncness.is_some() {
+        quote! {
+            Ok(std::boxed::Box::pin(async move {
+                let result: ::nashar_gah::khadim::caller::AsyncReturn = async move #body.await;
+                result?.await
+            }))
+        }
+    } else {
+        quote! {
             // Original function body
             let result = (|| {
                 // Execute the original function body
@@ -24,6 +48,41 @@ pub fn api_callback(_attr: TokenStream, input: TokenStream) -> TokenStream {
         }
     };
 
+    // Generate the transformed function
+
+    let transformed_fn = quote! {
+        pub fn #name(request: Request, mut writer: ResponseWriter) -> ::nashar_gah::khadim::caller::AsyncReturn {
+            #(#extractors)*
+
+            #run
+        }
+    };
+
+    transformed_fn.into()
+}
+
+fn is_type(ty: &Type, name: &str) -> bool {
+    match ty {
+        Type::Path(path) => path.path.segments.last().map(|segment| segment.ident == name).unwrap_or(false),
+        _ => false
+    }
+}
+
+#[proc_macro_attribute]
+pub fn ws_callback(_attr: TokenStream, input: TokenStream) -> TokenStream {
+    let input_fn = parse_macro_input!(input as ItemFn);
+
+    // Keep the argument names, the body runs for as long as the socket is open
+    let name = &input_fn.sig.ident;
+    let inputs = &input_fn.sig.inputs;
+    let body = &input_fn.block;
+
+    let transformed_fn = quote! {
+        pub fn #name(#inputs) -> std::pin::Pin<std::boxed::Box<dyn std::future::Future<Output = ()> + Send>> {
+            std::boxed::Box::pin(async move #body)
+        }
+    };
+
     transformed_fn.into()
 }
 